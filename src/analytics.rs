@@ -0,0 +1,278 @@
+//! Which parts of each video get watched, reported by the browser player.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{VideoEntry, http};
+
+/// Length of the pieces of a video whose watch time is counted.
+pub const BUCKET_SECONDS: u64 = 10;
+
+/// Beacons report what was played since the last one, every few seconds;
+/// longer ranges are bogus.
+const MAX_RANGE_SECONDS: f64 = 120.0;
+
+/// Videos longer than this are only counted up to here.
+const MAX_BUCKETS: usize = 24 * 3600 / BUCKET_SECONDS as usize;
+
+/// Columns of a rendered heatmap.
+const HEATMAP_COLUMNS: usize = 100;
+
+/// How often changed counts are written to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watched milliseconds per bucket and video, keyed by file name. Kept in
+/// the configured file (one tab separated video per line) or, without one,
+/// only until the server stops.
+///
+/// Beacons only change the counts in memory; [`flush_periodically`] writes
+/// them out, so a crash loses at most the last few seconds.
+pub struct Analytics {
+    path: Option<PathBuf>,
+    videos: Mutex<HashMap<String, Vec<u64>>>,
+    /// Whether the counts changed since they were last written.
+    dirty: AtomicBool,
+    /// Held while writing, so two flushes don't share the temporary file.
+    writing: Mutex<()>,
+}
+
+impl Analytics {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut videos = HashMap::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(content) => videos = content.lines().filter_map(parse_line).collect(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Analytics {
+            path,
+            videos: Mutex::new(videos),
+            dirty: AtomicBool::new(false),
+            writing: Mutex::new(()),
+        })
+    }
+
+    /// Adds played `(start, end)` ranges, in seconds, to `name`'s counts.
+    pub fn record(&self, name: &str, ranges: &[(f64, f64)]) {
+        if name.contains(['\n', '\r']) {
+            return;
+        }
+        let mut videos = self.videos.lock().unwrap();
+        let buckets = videos.entry(name.to_string()).or_default();
+
+        for &(start, end) in ranges {
+            if !(start >= 0.0 && end > start && end - start <= MAX_RANGE_SECONDS) {
+                continue;
+            }
+            let (start, end) = ((start * 1000.0) as u64, (end * 1000.0) as u64);
+            let bucket_ms = BUCKET_SECONDS * 1000;
+
+            let mut pos = start;
+            while pos < end {
+                let bucket = (pos / bucket_ms) as usize;
+                if bucket >= MAX_BUCKETS {
+                    break;
+                }
+                let bucket_end = ((bucket as u64 + 1) * bucket_ms).min(end);
+                if buckets.len() <= bucket {
+                    buckets.resize(bucket + 1, 0);
+                }
+                buckets[bucket] += bucket_end - pos;
+                pos = bucket_end;
+            }
+        }
+
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Watched milliseconds per bucket of every video with data, by name.
+    pub fn snapshot(&self) -> Vec<(String, Vec<u64>)> {
+        let mut videos: Vec<(String, Vec<u64>)> = self
+            .videos
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, buckets)| !buckets.is_empty())
+            .map(|(name, buckets)| (name.clone(), buckets.clone()))
+            .collect();
+        videos.sort();
+        videos
+    }
+
+    /// Writes the counts to the file if they changed since the last write.
+    /// This blocks on the disk, so async code runs it through
+    /// `spawn_blocking`.
+    pub fn flush(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _writing = self.writing.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // Beacons only wait for the copy, not for the disk
+        let content: String = self
+            .videos
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, buckets)| {
+                let buckets: Vec<String> = buckets.iter().map(u64::to_string).collect();
+                format!("{}\t{}\n", name, buckets.join(","))
+            })
+            .collect();
+
+        // Write then rename so a crash never leaves a truncated file
+        let tmp = path.with_extension("tmp");
+        let result = fs::write(&tmp, content).and_then(|()| fs::rename(tmp, path));
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// Flushes `analytics` every few seconds, off the async workers.
+pub async fn flush_periodically(analytics: Arc<Analytics>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let analytics = analytics.clone();
+        if let Ok(Err(err)) = tokio::task::spawn_blocking(move || analytics.flush()).await {
+            eprintln!("Failed to save analytics: {}", err);
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, Vec<u64>)> {
+    let (name, buckets) = line.rsplit_once('\t')?;
+    let buckets = buckets
+        .split(',')
+        .map(|ms| ms.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    Some((name.to_string(), buckets))
+}
+
+fn format_time(seconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Renders a heatmap for every video with data. The heatmaps of the files in
+/// `videos` span their whole running time, so drop-off at the end shows.
+pub fn generate_heatmap_html(analytics: &Analytics, videos: &[VideoEntry]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit - Playback Analytics</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; }
+        h1 { color: #333; }
+        .video { margin: 20px 0; }
+        .video-name { font-weight: bold; margin-bottom: 5px; }
+        .video-total { font-size: 0.9em; color: #666; margin-bottom: 5px; }
+        .heatmap { display: flex; height: 30px; border: 1px solid #ccc; border-radius: 3px; overflow: hidden; }
+        .heatmap div { flex: 1; background-color: #d9534f; }
+        .axis { display: flex; justify-content: space-between; font-size: 0.8em; color: #666; }
+    </style>
+</head>
+<body>
+    <h1>Playback Analytics</h1>
+    <p><a href="/">Back to the catalog</a></p>
+"#,
+    );
+
+    let watched = analytics.snapshot();
+    if watched.is_empty() {
+        html.push_str("<p>Nothing has been watched in the browser player yet.</p>");
+    }
+
+    for (name, mut buckets) in watched {
+        let duration = videos
+            .iter()
+            .find(|video| video.path.file_name().and_then(|n| n.to_str()) == Some(&name))
+            .and_then(|video| video.duration);
+        if let Some(duration) = duration {
+            let len = (duration.as_secs().div_ceil(BUCKET_SECONDS) as usize).min(MAX_BUCKETS);
+            if len > buckets.len() {
+                buckets.resize(len, 0);
+            }
+        }
+
+        let total: u64 = buckets.iter().sum();
+        let per_column = buckets.len().div_ceil(HEATMAP_COLUMNS);
+        let columns: Vec<u64> = buckets
+            .chunks(per_column)
+            .map(|chunk| chunk.iter().sum::<u64>() / chunk.len() as u64)
+            .collect();
+        let peak = columns.iter().copied().max().unwrap_or(1).max(1);
+        let column_seconds = per_column as u64 * BUCKET_SECONDS;
+
+        html.push_str(&format!(
+            r#"<div class="video"><div class="video-name">{}</div><div class="video-total">{} watched in total</div><div class="heatmap">"#,
+            http::html_escape(&name),
+            format_time(total / 1000)
+        ));
+        for (i, value) in columns.iter().enumerate() {
+            let start = i as u64 * column_seconds;
+            html.push_str(&format!(
+                r#"<div style="opacity: {:.2}" title="{} - {}: {}% of the peak"></div>"#,
+                *value as f64 / peak as f64,
+                format_time(start),
+                format_time(start + column_seconds),
+                value * 100 / peak
+            ));
+        }
+        html.push_str(&format!(
+            r#"</div><div class="axis"><span>0:00:00</span><span>{}</span></div></div>"#,
+            format_time(buckets.len() as u64 * BUCKET_SECONDS)
+        ));
+    }
+
+    html.push_str("</body></html>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_path;
+
+    #[test]
+    fn writes_only_changed_counts() {
+        let path = temp_path("analytics.tsv");
+        let analytics = Analytics::load(Some(path.clone())).unwrap();
+
+        analytics.record("Movie.mkv", &[(5.0, 15.0)]);
+        assert!(!path.exists(), "beacons must not write");
+        analytics.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Movie.mkv\t5000,5000\n");
+
+        fs::remove_file(&path).unwrap();
+        analytics.flush().unwrap();
+        assert!(!path.exists(), "nothing changed since the last write");
+
+        analytics.record("Movie.mkv", &[(15.0, 20.0)]);
+        analytics.flush().unwrap();
+        let reloaded = Analytics::load(Some(path.clone())).unwrap();
+        assert_eq!(
+            reloaded.snapshot(),
+            vec![("Movie.mkv".to_string(), vec![5000, 10000])]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+
+use crate::VideoEntry;
+use crate::analytics::{self, Analytics};
+use crate::api_keys::{self, ApiKey, ApiKeys, Scope};
+use crate::auth::ApiCaller;
+use crate::guests::{Guest, Guests};
+use crate::json::{self, Value};
+use crate::maintenance::{self, Maintenance};
+use crate::patterns::VideoPatterns;
+use crate::routes::PathParam;
+use crate::state::{AppState, Library};
+
+/// Largest beacon body accepted. The player sends one every 15 seconds,
+/// usually with a single range, so this leaves room for a lot of seeking.
+const BEACON_LIMIT: usize = 4 * 1024;
+
+#[derive(Clone, Copy)]
+pub enum Endpoint {
+    Videos,
+    Rescan,
+    ListKeys,
+    CreateKey,
+    RevokeKey(PathParam),
+    ListGuests,
+    CreateGuest,
+    RevokeGuest(PathParam),
+    Beacon,
+    Analytics,
+    MaintenanceStatus,
+    StartMaintenance,
+    EndMaintenance,
+}
+
+impl Endpoint {
+    fn scope(&self) -> Scope {
+        match self {
+            Endpoint::Videos => Scope::ReadCatalog,
+            Endpoint::Beacon => Scope::ReportPlayback,
+            Endpoint::Rescan => Scope::ManageMedia,
+            Endpoint::ListKeys
+            | Endpoint::CreateKey
+            | Endpoint::RevokeKey(_)
+            | Endpoint::ListGuests
+            | Endpoint::CreateGuest
+            | Endpoint::RevokeGuest(_)
+            | Endpoint::Analytics
+            | Endpoint::MaintenanceStatus
+            | Endpoint::StartMaintenance
+            | Endpoint::EndMaintenance => Scope::Admin,
+        }
+    }
+}
+
+/// Dispatches an `/api` request from `caller` to `endpoint`, if the path is
+/// one. A browser in the kid profile only gets the videos it shows and may
+/// report playback, whatever its scopes; a guest only reaches its videos.
+pub async fn handle(
+    req: Request<Incoming>,
+    endpoint: Option<Endpoint>,
+    caller: Option<ApiCaller>,
+    state: &AppState,
+    server_url: &str,
+    kid: bool,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let api_keys = &state.auth.api_keys;
+    let analytics = &state.analytics;
+    let maintenance = &state.maintenance;
+    let Some(ApiCaller { scopes, session }) = caller else {
+        return error(StatusCode::UNAUTHORIZED, "missing or invalid API key");
+    };
+    let Some(endpoint) = endpoint else {
+        return error(StatusCode::NOT_FOUND, "no such endpoint");
+    };
+    if kid && !matches!(endpoint, Endpoint::Videos | Endpoint::Beacon) {
+        return error(StatusCode::FORBIDDEN, "not available in the kid profile");
+    }
+    if !api_keys::allows(&scopes, endpoint.scope()) {
+        return error(
+            StatusCode::FORBIDDEN,
+            &format!("requires the {} scope", endpoint.scope().as_str()),
+        );
+    }
+
+    let visible = |video: &VideoEntry| {
+        (!kid || state.config.kids.allows(video))
+            && session.as_ref().is_none_or(|session| session.allows(video))
+    };
+
+    match endpoint {
+        Endpoint::Videos => {
+            let videos: Vec<VideoEntry> = state
+                .library
+                .videos()
+                .iter()
+                .filter(|video| visible(video))
+                .cloned()
+                .collect();
+            json_response(StatusCode::OK, videos_json(&videos, server_url))
+        }
+        Endpoint::Rescan => {
+            state.library.rescan();
+            json_response(
+                StatusCode::OK,
+                videos_json(&state.library.videos(), server_url),
+            )
+        }
+        Endpoint::ListKeys => {
+            let keys: Vec<String> = api_keys.list().iter().map(key_json).collect();
+            json_response(StatusCode::OK, format!("[{}]", keys.join(",")))
+        }
+        Endpoint::CreateKey => create_key(req, api_keys).await,
+        Endpoint::RevokeKey(id) => match api_keys.revoke(id.get(req.uri().path())) {
+            Ok(true) => json_response(StatusCode::OK, "{\"revoked\":true}".to_string()),
+            Ok(false) => error(StatusCode::NOT_FOUND, "no such key"),
+            Err(err) => {
+                eprintln!("Failed to save API keys: {}", err);
+                error(StatusCode::INTERNAL_SERVER_ERROR, "failed to save API keys")
+            }
+        },
+        Endpoint::ListGuests => {
+            let guests: Vec<String> = state.auth.guests.list().iter().map(guest_json).collect();
+            json_response(StatusCode::OK, format!("[{}]", guests.join(",")))
+        }
+        Endpoint::CreateGuest => create_guest(req, &state.auth.guests, server_url).await,
+        Endpoint::RevokeGuest(id) => {
+            if state.auth.guests.revoke(id.get(req.uri().path())) {
+                json_response(StatusCode::OK, "{\"revoked\":true}".to_string())
+            } else {
+                error(StatusCode::NOT_FOUND, "no such guest")
+            }
+        }
+        Endpoint::Beacon => record_beacon(req, &state.library, analytics, visible).await,
+        Endpoint::Analytics => {
+            let entries: Vec<String> = analytics
+                .snapshot()
+                .iter()
+                .map(|(name, buckets)| {
+                    let seconds: Vec<String> = buckets
+                        .iter()
+                        .map(|ms| format!("{}", *ms as f64 / 1000.0))
+                        .collect();
+                    format!(
+                        "{{\"name\":{},\"bucket_seconds\":{},\"watched\":[{}]}}",
+                        json::quote(name),
+                        analytics::BUCKET_SECONDS,
+                        seconds.join(",")
+                    )
+                })
+                .collect();
+            json_response(StatusCode::OK, format!("[{}]", entries.join(",")))
+        }
+        Endpoint::MaintenanceStatus => json_response(
+            StatusCode::OK,
+            maintenance_json(maintenance.status().as_ref()),
+        ),
+        Endpoint::StartMaintenance => start_maintenance(req, maintenance).await,
+        Endpoint::EndMaintenance => {
+            maintenance.end();
+            json_response(StatusCode::OK, maintenance_json(None))
+        }
+    }
+}
+
+/// Turns maintenance mode on with an optional `message` for viewers and the
+/// expected `duration` in seconds.
+async fn start_maintenance(
+    req: Request<Incoming>,
+    maintenance: &Maintenance,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match Limited::new(req.into_body(), 16 * 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error(StatusCode::BAD_REQUEST, "invalid request body"),
+    };
+    let request = if body.is_empty() {
+        Value::Object(Default::default())
+    } else {
+        match json::parse(&String::from_utf8_lossy(&body)) {
+            Ok(request) => request,
+            Err(err) => return error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {}", err)),
+        }
+    };
+
+    let message = request.get("message").and_then(Value::as_str).unwrap_or("");
+    let duration = match request.get("duration") {
+        None | Some(Value::Null) => None,
+        Some(Value::Number(seconds)) if *seconds >= 0.0 => {
+            match Duration::try_from_secs_f64(*seconds) {
+                Ok(duration) => Some(duration),
+                Err(_) => return error(StatusCode::BAD_REQUEST, "duration out of range"),
+            }
+        }
+        Some(_) => return error(StatusCode::BAD_REQUEST, "duration must be seconds"),
+    };
+
+    match maintenance.start(message.to_string(), duration) {
+        Some(status) => json_response(StatusCode::OK, maintenance_json(Some(&status))),
+        None => error(StatusCode::BAD_REQUEST, "duration out of range"),
+    }
+}
+
+fn maintenance_json(status: Option<&maintenance::Status>) -> String {
+    match status {
+        Some(status) => format!(
+            "{{\"enabled\":true,\"message\":{},\"retry_after\":{}}}",
+            json::quote(&status.message),
+            status.retry_after()
+        ),
+        None => "{\"enabled\":false}".to_string(),
+    }
+}
+
+/// Records the time ranges a player reports as watched, as
+/// `{"video": alias, "ranges": [[start, end], ...]}` in seconds, for a video
+/// the caller can see.
+async fn record_beacon(
+    req: Request<Incoming>,
+    library: &Library,
+    analytics: &Analytics,
+    visible: impl Fn(&VideoEntry) -> bool,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match Limited::new(req.into_body(), BEACON_LIMIT).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error(StatusCode::BAD_REQUEST, "invalid request body"),
+    };
+    let beacon = match json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(beacon) => beacon,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {}", err)),
+    };
+
+    let alias = beacon.get("video").and_then(Value::as_str).unwrap_or("");
+    let Some(video) = library
+        .videos()
+        .iter()
+        .find(|video| video.alias == alias && visible(video))
+        .cloned()
+    else {
+        return error(StatusCode::NOT_FOUND, "no such video");
+    };
+    let name = video
+        .path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    // Episodes play from the start of their chapter
+    let offset = video
+        .episode
+        .map_or(0.0, |episode| episode.clip.start.as_secs_f64());
+
+    let mut ranges = Vec::new();
+    for range in beacon
+        .get("ranges")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+    {
+        match range.as_array().unwrap_or_default() {
+            [Value::Number(start), Value::Number(end)] => {
+                ranges.push((start + offset, end + offset))
+            }
+            _ => return error(StatusCode::BAD_REQUEST, "ranges must be [start, end] pairs"),
+        }
+    }
+
+    analytics.record(name, &ranges);
+    json_response(StatusCode::OK, "{\"recorded\":true}".to_string())
+}
+
+async fn create_key(
+    req: Request<Incoming>,
+    api_keys: &ApiKeys,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match Limited::new(req.into_body(), 16 * 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error(StatusCode::BAD_REQUEST, "invalid request body"),
+    };
+    let request = match json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(request) => request,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {}", err)),
+    };
+
+    let name = request.get("name").and_then(Value::as_str).unwrap_or("");
+    let mut scopes = Vec::new();
+    for scope in request
+        .get("scopes")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+    {
+        match scope.as_str().and_then(Scope::parse) {
+            Some(scope) => scopes.push(scope),
+            None => return error(StatusCode::BAD_REQUEST, "unknown scope"),
+        }
+    }
+    if scopes.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "at least one scope is required");
+    }
+
+    match api_keys.create(name, scopes) {
+        Ok((key, secret)) => {
+            let mut body = key_json(&key);
+            body.pop();
+            body.push_str(&format!(",\"key\":{}}}", json::quote(&secret)));
+            json_response(StatusCode::CREATED, body)
+        }
+        Err(err) => {
+            eprintln!("Failed to save API keys: {}", err);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "failed to save API keys")
+        }
+    }
+}
+
+/// Creates a guest account from `{"name": ..., "videos": [pattern, ...],
+/// "duration": seconds}`, a day by default, returning its link.
+async fn create_guest(
+    req: Request<Incoming>,
+    guests: &Guests,
+    server_url: &str,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match Limited::new(req.into_body(), 16 * 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error(StatusCode::BAD_REQUEST, "invalid request body"),
+    };
+    let request = match json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(request) => request,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &format!("invalid JSON: {}", err)),
+    };
+
+    let name = match request.get("name").and_then(Value::as_str) {
+        Some(name) if !name.trim().is_empty() => name.trim(),
+        _ => "guest",
+    };
+    let mut videos = Vec::new();
+    for pattern in request
+        .get("videos")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+    {
+        match pattern.as_str() {
+            Some(pattern) if !pattern.is_empty() => videos.push(pattern.to_string()),
+            _ => return error(StatusCode::BAD_REQUEST, "videos must be file name patterns"),
+        }
+    }
+    if videos.is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "at least one video pattern is required",
+        );
+    }
+    let ttl = match request.get("duration") {
+        None | Some(Value::Null) => Duration::from_secs(24 * 60 * 60),
+        Some(Value::Number(seconds)) if *seconds > 0.0 => {
+            match Duration::try_from_secs_f64(*seconds) {
+                Ok(ttl) => ttl,
+                Err(_) => return error(StatusCode::BAD_REQUEST, "duration out of range"),
+            }
+        }
+        Some(_) => return error(StatusCode::BAD_REQUEST, "duration must be seconds"),
+    };
+
+    let Some((guest, token)) = guests.create(name, VideoPatterns::new(videos), ttl) else {
+        return error(StatusCode::BAD_REQUEST, "duration out of range");
+    };
+    let mut body = guest_json(&guest);
+    body.pop();
+    body.push_str(&format!(
+        ",\"link\":{}}}",
+        json::quote(&format!("{}/guest/{}", server_url, token))
+    ));
+    json_response(StatusCode::CREATED, body)
+}
+
+fn videos_json(videos: &[VideoEntry], server_url: &str) -> String {
+    let entries: Vec<String> = videos
+        .iter()
+        .map(|video| {
+            let name = video
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            let problem = match &video.problem {
+                Some(problem) => json::quote(problem),
+                None => "null".to_string(),
+            };
+            let extra_of = match &video.extra_of {
+                Some(alias) => json::quote(alias),
+                None => "null".to_string(),
+            };
+            let episode = match &video.episode {
+                Some(episode) => format!(
+                    "{{\"of\":{},\"title\":{},\"start\":{},\"end\":{}}}",
+                    json::quote(&episode.of),
+                    json::quote(&episode.title),
+                    episode.clip.start.as_secs_f64(),
+                    episode.clip.end.as_secs_f64()
+                ),
+                None => "null".to_string(),
+            };
+            format!(
+                "{{\"alias\":{},\"name\":{},\"url\":{},\"problem\":{},\"extra_of\":{},\"episode\":{}}}",
+                json::quote(&video.alias),
+                json::quote(name),
+                json::quote(&format!("{}/{}", server_url, video.alias)),
+                problem,
+                extra_of,
+                episode
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+fn key_json(key: &ApiKey) -> String {
+    let scopes: Vec<String> = key
+        .scopes
+        .iter()
+        .map(|scope| json::quote(scope.as_str()))
+        .collect();
+    format!(
+        "{{\"id\":{},\"name\":{},\"scopes\":[{}],\"created\":{}}}",
+        json::quote(&key.id),
+        json::quote(&key.name),
+        scopes.join(","),
+        key.created
+    )
+}
+
+fn guest_json(guest: &Guest) -> String {
+    let videos: Vec<String> = guest
+        .videos
+        .as_slice()
+        .iter()
+        .map(|pattern| json::quote(pattern))
+        .collect();
+    format!(
+        "{{\"id\":{},\"name\":{},\"videos\":[{}],\"expires\":{}}}",
+        json::quote(&guest.id),
+        json::quote(&guest.name),
+        videos.join(","),
+        guest.expires
+    )
+}
+
+fn json_response(status: StatusCode, body: String) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap();
+    Ok(response)
+}
+
+fn error(status: StatusCode, message: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    json_response(status, format!("{{\"error\":{}}}", json::quote(message)))
+}
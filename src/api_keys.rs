@@ -0,0 +1,199 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+use crate::crypto;
+
+const KEY_PREFIX: &str = "ssk";
+
+/// What an API key (or session) is allowed to do on `/api` routes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    ReadCatalog,
+    /// Posting the player's playback reports.
+    ReportPlayback,
+    ManageMedia,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadCatalog => "read-catalog",
+            Scope::ReportPlayback => "report-playback",
+            Scope::ManageMedia => "manage-media",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "read-catalog" => Some(Scope::ReadCatalog),
+            "report-playback" => Some(Scope::ReportPlayback),
+            "manage-media" => Some(Scope::ManageMedia),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `granted` covers `needed`; the admin scope covers everything.
+pub fn allows(granted: &[Scope], needed: Scope) -> bool {
+    granted.contains(&Scope::Admin) || granted.contains(&needed)
+}
+
+#[derive(Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: u64,
+    hash: [u8; 32],
+}
+
+/// API keys for scripts and integrations.
+///
+/// A key looks like `ssk_<id>_<secret>`; only its SHA-256 is kept, so the
+/// full key is shown once when it is created. Keys are written to the
+/// configured file (one tab separated key per line) or, without one, only
+/// live until the server stops.
+pub struct ApiKeys {
+    path: Option<PathBuf>,
+    keys: RwLock<Vec<ApiKey>>,
+}
+
+impl ApiKeys {
+    pub fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let mut keys = Vec::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(content) => keys = content.lines().filter_map(parse_line).collect(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(ApiKeys {
+            path,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Creates a key, returning its description and the secret key itself.
+    pub fn create(&self, name: &str, scopes: Vec<Scope>) -> io::Result<(ApiKey, String)> {
+        let id = crypto::to_hex(&crypto::random_bytes(4));
+        let secret = format!(
+            "{}_{}_{}",
+            KEY_PREFIX,
+            id,
+            URL_SAFE_NO_PAD.encode(crypto::random_bytes(24))
+        );
+        let key = ApiKey {
+            id,
+            name: name.replace(['\t', '\n', '\r'], " "),
+            scopes,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            hash: crypto::sha256(secret.as_bytes()),
+        };
+
+        let mut keys = self.keys.write().unwrap();
+        keys.push(key.clone());
+        self.save(&keys)?;
+        Ok((key, secret))
+    }
+
+    /// Revokes the key with `id`, returning whether it existed.
+    pub fn revoke(&self, id: &str) -> io::Result<bool> {
+        let mut keys = self.keys.write().unwrap();
+        let before = keys.len();
+        keys.retain(|key| key.id != id);
+        if keys.len() == before {
+            return Ok(false);
+        }
+        self.save(&keys)?;
+        Ok(true)
+    }
+
+    /// Returns the scopes of a presented key, if it is valid.
+    pub fn verify(&self, secret: &str) -> Option<Vec<Scope>> {
+        let mut parts = secret.splitn(3, '_');
+        if parts.next()? != KEY_PREFIX {
+            return None;
+        }
+        let id = parts.next()?;
+        let hash = crypto::sha256(secret.as_bytes());
+
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|key| key.id == id && crypto::constant_time_eq(&key.hash, &hash))
+            .map(|key| key.scopes.clone())
+    }
+
+    fn save(&self, keys: &[ApiKey]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let content: String = keys
+            .iter()
+            .map(|key| {
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\n",
+                    key.id,
+                    crypto::to_hex(&key.hash),
+                    key.scopes
+                        .iter()
+                        .map(|scope| scope.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    key.created,
+                    key.name
+                )
+            })
+            .collect();
+
+        // Write then rename so a crash never leaves a truncated key file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, path)
+    }
+}
+
+fn parse_line(line: &str) -> Option<ApiKey> {
+    let mut fields = line.splitn(5, '\t');
+    let id = fields.next()?.to_string();
+    let hex = fields.next()?;
+    let scopes = fields.next()?.split(',').filter_map(Scope::parse).collect();
+    let created = fields.next()?.parse().ok()?;
+    let name = fields.next().unwrap_or_default().to_string();
+
+    let mut hash = [0u8; 32];
+    if hex.len() != 64 {
+        return None;
+    }
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(ApiKey {
+        id,
+        name,
+        scopes,
+        created,
+        hash,
+    })
+}
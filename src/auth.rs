@@ -0,0 +1,113 @@
+use std::net::IpAddr;
+
+use hyper::Request;
+use hyper::header::AUTHORIZATION;
+
+use crate::api_keys::{ApiKeys, Scope};
+use crate::forward_auth::ForwardAuth;
+use crate::guests::Guests;
+use crate::oidc::Oidc;
+use crate::session::{Role, Session, Sessions};
+
+/// The scopes of an `/api` request, and the session it was made with: a
+/// guest's session still limits which videos it reaches.
+#[derive(Clone)]
+pub struct ApiCaller {
+    pub scopes: Vec<Scope>,
+    pub session: Option<Session>,
+}
+
+/// Every way a request can prove who it comes from.
+pub struct Auth {
+    pub sessions: Sessions,
+    pub oidc: Option<Oidc>,
+    pub forward_auth: Option<ForwardAuth>,
+    pub api_keys: ApiKeys,
+    pub guests: Guests,
+}
+
+impl Auth {
+    /// Whether anonymous requests are turned away. Without any login method
+    /// configured the whole server is open, as it always was.
+    pub fn login_required(&self) -> bool {
+        self.sessions.is_enabled() || self.forward_auth.is_some()
+    }
+
+    pub fn authenticate<B>(&self, peer: IpAddr, req: &Request<B>) -> Option<Session> {
+        self.forward_auth
+            .as_ref()
+            .and_then(|forward_auth| forward_auth.authenticate(peer, req))
+            .or_else(|| self.sessions.authenticate(req))
+            .and_then(|session| self.resolve_guest(session))
+    }
+
+    /// Fills in what a guest session may watch. Guest cookies only carry
+    /// the guest's id, so an expired or revoked guest is logged out.
+    fn resolve_guest(&self, session: Session) -> Option<Session> {
+        if session.role != Role::Guest {
+            return Some(session);
+        }
+        let guest = self.guests.get(&session.user)?;
+        Some(Session {
+            user: guest.name,
+            role: Role::Guest,
+            videos: Some(guest.videos),
+        })
+    }
+
+    /// Resolves what an `/api` request may do.
+    ///
+    /// A presented API key (`Authorization: Bearer` or `X-Api-Key`) wins and
+    /// must be valid. Otherwise a logged in admin gets every scope, a viewer
+    /// may read the catalog and report playback, and a guest may only report
+    /// playback. `None` means the request is unauthorized.
+    pub fn api_caller<B>(&self, peer: IpAddr, req: &Request<B>) -> Option<ApiCaller> {
+        if let Some(key) = presented_api_key(req) {
+            return self.api_keys.verify(key).map(|scopes| ApiCaller {
+                scopes,
+                session: None,
+            });
+        }
+
+        let session = self.authenticate(peer, req);
+        let scopes = match &session {
+            Some(session) if session.role == Role::Admin => vec![Scope::Admin],
+            Some(session) if session.role == Role::Guest => vec![Scope::ReportPlayback],
+            Some(_) => vec![Scope::ReadCatalog, Scope::ReportPlayback],
+            None if !self.login_required() => vec![Scope::Admin],
+            None => return None,
+        };
+        Some(ApiCaller { scopes, session })
+    }
+
+    /// Whether the request comes from an admin, who gets past maintenance
+    /// mode. On an open server, where everybody would be an admin, only
+    /// local connections and admin API keys are.
+    pub fn is_maintainer<B>(&self, peer: IpAddr, req: &Request<B>) -> bool {
+        if let Some(key) = presented_api_key(req) {
+            return self
+                .api_keys
+                .verify(key)
+                .is_some_and(|scopes| scopes.contains(&Scope::Admin));
+        }
+        if !self.login_required() {
+            return peer.is_loopback();
+        }
+        self.authenticate(peer, req)
+            .is_some_and(|session| session.role == Role::Admin)
+    }
+}
+
+/// The API key sent as `Authorization: Bearer` or `X-Api-Key`, if any.
+fn presented_api_key<B>(req: &Request<B>) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            req.headers()
+                .get("X-Api-Key")
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(str::trim)
+}
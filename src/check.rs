@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{get_video_list, probe};
+
+/// Problems found by the last `streamshit check`, stored next to the videos
+/// so the server can flag them in the catalog.
+pub const REPORT_FILE: &str = ".streamshit-check";
+
+/// Checks every video in `video_dir`, also decoding it with `ffmpeg` if
+/// given, prints the results and records the problems in the report.
+/// Returns the number of problem files.
+pub fn run(video_dir: &str, ffmpeg: Option<&str>) -> usize {
+    let videos: Vec<_> = get_video_list(video_dir)
+        .into_iter()
+        .filter(|video| video.episode.is_none())
+        .collect();
+    let mut problems = Vec::new();
+
+    for video in &videos {
+        let name = video
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        let mut result = probe::validate(&video.path);
+        if result.is_ok()
+            && let Some(ffmpeg) = ffmpeg
+        {
+            result = decode(ffmpeg, &video.path);
+        }
+
+        match result {
+            Ok(()) => println!("OK       {}", name),
+            Err(problem) => {
+                println!("CORRUPT  {}: {}", name, problem);
+                problems.push((name.to_string(), problem));
+            }
+        }
+    }
+
+    let report: String = problems
+        .iter()
+        .map(|(name, problem)| format!("{}\t{}\n", name, problem.replace(['\t', '\n'], " ")))
+        .collect();
+    let report_path = Path::new(video_dir).join(REPORT_FILE);
+    if let Err(err) = fs::write(&report_path, report) {
+        eprintln!("Failed to write {}: {}", report_path.display(), err);
+    }
+
+    println!(
+        "Checked {} video files, {} with problems.",
+        videos.len(),
+        problems.len()
+    );
+    problems.len()
+}
+
+/// Loads the problems recorded by the last check, keyed by file name.
+pub fn load_report(video_dir: &str) -> HashMap<String, String> {
+    fs::read_to_string(Path::new(video_dir).join(REPORT_FILE))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, problem)| (name.to_string(), problem.to_string()))
+        .collect()
+}
+
+/// Decodes the whole file with ffmpeg, failing on any reported error.
+fn decode(ffmpeg: &str, path: &Path) -> Result<(), String> {
+    let output = Command::new(ffmpeg)
+        .args(["-nostdin", "-v", "error", "-i"])
+        .arg(path)
+        .args(["-f", "null", "-"])
+        .stdout(Stdio::null())
+        .output()
+        .map_err(|e| format!("could not run {}: {}", ffmpeg, e))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => Err(format!("decode error: {}", line.trim())),
+        None if !output.status.success() => {
+            Err(format!("{} exited with {}", ffmpeg, output.status))
+        }
+        None => Ok(()),
+    }
+}
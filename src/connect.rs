@@ -0,0 +1,304 @@
+//! The /connect page: every URL the server can be reached at, with QR codes
+//! to open them on a phone or TV.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::{http, qr};
+
+/// Where the server listens, to work out the URLs it answers on.
+pub struct Addresses {
+    pub scheme: &'static str,
+    /// The address bound to; unspecified means every interface.
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Tunnel or reverse proxy URL the server is also reachable through.
+    pub public_url: Option<String>,
+}
+
+pub struct ServerUrl {
+    pub label: String,
+    pub url: String,
+    /// Caveat shown with the URL, if it only works in some cases.
+    pub note: Option<&'static str>,
+}
+
+impl Addresses {
+    /// The URLs to try, best first. Interfaces are listed anew on every
+    /// call, so addresses handed out after startup show up.
+    pub fn urls(&self) -> Vec<ServerUrl> {
+        let mut urls = Vec::new();
+        if let Some(url) = &self.public_url {
+            urls.push(ServerUrl {
+                label: "Public URL".to_string(),
+                url: url.trim_end_matches('/').to_string(),
+                note: None,
+            });
+        }
+
+        let mut link_local = Vec::new();
+        for interface in interface_addresses() {
+            let addr = interface.network.addr();
+            if addr.is_loopback() || !self.listens_on(addr) {
+                continue;
+            }
+            let Interface { name, scope, .. } = interface;
+            let url = match addr {
+                IpAddr::V4(addr) => ServerUrl {
+                    label: format!("IPv4 ({})", name),
+                    url: format_url(self.scheme, IpAddr::V4(addr), self.port),
+                    note: addr
+                        .is_link_local()
+                        .then_some("Self-assigned, only reachable on this network segment"),
+                },
+                IpAddr::V6(addr) if addr.is_unicast_link_local() => {
+                    // The zone names the interface, but most browsers
+                    // refuse URLs with one
+                    let zone = if scope == 0 {
+                        String::new()
+                    } else {
+                        format!("%25{}", name)
+                    };
+                    link_local.push(ServerUrl {
+                        label: format!("IPv6 link-local ({})", name),
+                        url: format!("{}://[{}{}]:{}", self.scheme, addr, zone, self.port),
+                        note: Some(
+                            "Works in players such as VLC and mpv, but not in most browsers",
+                        ),
+                    });
+                    continue;
+                }
+                IpAddr::V6(addr) => ServerUrl {
+                    label: format!("IPv6 ({})", name),
+                    url: format_url(self.scheme, IpAddr::V6(addr), self.port),
+                    note: None,
+                },
+            };
+            urls.push(url);
+        }
+
+        if self.bind.is_unspecified()
+            && let Some(hostname) = hostname()
+        {
+            let name = hostname.split('.').next().unwrap_or_default();
+            if !name.is_empty() {
+                urls.push(ServerUrl {
+                    label: "Zeroconf name".to_string(),
+                    url: format!("{}://{}.local:{}", self.scheme, name, self.port),
+                    note: Some("Needs Avahi or Bonjour running on this machine"),
+                });
+            }
+        }
+        urls.extend(link_local);
+        urls
+    }
+
+    /// The server URL for a client at `client` that connected to `local`:
+    /// the address of the interface on the client's subnet, so Ethernet,
+    /// Wi-Fi and VPN clients each get links they can reach. Clients on no
+    /// local subnet (routed, or behind NAT) get the address they used.
+    pub fn url_for(&self, client: IpAddr, local: IpAddr) -> String {
+        let addr = interface_addresses()
+            .into_iter()
+            .map(|interface| interface.network)
+            .find(|network| {
+                let addr = network.addr();
+                // Link-local peers would need their own zone in the URL
+                let link_local = matches!(addr, IpAddr::V6(v6) if v6.is_unicast_link_local());
+                !link_local && self.listens_on(addr) && network.contains(&client)
+            })
+            .map(|network| network.addr())
+            .unwrap_or(local);
+        format_url(self.scheme, addr, self.port)
+    }
+
+    fn listens_on(&self, addr: IpAddr) -> bool {
+        match self.bind {
+            // Binding to 0.0.0.0 only accepts IPv4, binding to :: accepts
+            // both unless the system is set up for IPv6 only
+            IpAddr::V4(bind) if bind.is_unspecified() => addr.is_ipv4(),
+            IpAddr::V6(bind) if bind.is_unspecified() => true,
+            bind => bind == addr,
+        }
+    }
+}
+
+fn format_url(scheme: &str, addr: IpAddr, port: u16) -> String {
+    match addr {
+        IpAddr::V4(addr) => format!("{}://{}:{}", scheme, addr, port),
+        IpAddr::V6(addr) => format!("{}://[{}]:{}", scheme, addr, port),
+    }
+}
+
+/// An address of a network interface.
+struct Interface {
+    name: String,
+    /// The address, with the prefix length of its subnet.
+    network: IpNet,
+    /// IPv6 scope ID.
+    scope: u32,
+}
+
+/// Addresses of the interfaces that are up.
+#[cfg(unix)]
+fn interface_addresses() -> Vec<Interface> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addrs = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: `getifaddrs` fills `list` with a linked list we free below.
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return addrs;
+    }
+
+    let mut entry = list;
+    while !entry.is_null() {
+        // SAFETY: `entry` is a node of the list returned by `getifaddrs`,
+        // and `ifa_addr` points to a socket address of the family it names.
+        unsafe {
+            let ifa = &*entry;
+            entry = ifa.ifa_next;
+            if ifa.ifa_addr.is_null() || ifa.ifa_flags & libc::IFF_UP as libc::c_uint == 0 {
+                continue;
+            }
+            let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
+            // The netmask has the family of the address
+            let has_mask = !ifa.ifa_netmask.is_null();
+            let (addr, prefix, scope) = match (*ifa.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    let prefix = if has_mask {
+                        let mask = &*(ifa.ifa_netmask as *const libc::sockaddr_in);
+                        mask.sin_addr.s_addr.count_ones() as u8
+                    } else {
+                        32
+                    };
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    (IpAddr::V4(ip), prefix, 0)
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    let prefix = if has_mask {
+                        let mask = &*(ifa.ifa_netmask as *const libc::sockaddr_in6);
+                        mask.sin6_addr
+                            .s6_addr
+                            .iter()
+                            .map(|b| b.count_ones())
+                            .sum::<u32>() as u8
+                    } else {
+                        128
+                    };
+                    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    (IpAddr::V6(ip), prefix, addr.sin6_scope_id)
+                }
+                _ => continue,
+            };
+            if let Ok(network) = IpNet::new(addr, prefix) {
+                addrs.push(Interface {
+                    name,
+                    network,
+                    scope,
+                });
+            }
+        }
+    }
+
+    // SAFETY: `list` came from a successful `getifaddrs`.
+    unsafe { libc::freeifaddrs(list) };
+    addrs
+}
+
+#[cfg(not(unix))]
+fn interface_addresses() -> Vec<Interface> {
+    Vec::new()
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is writable for its whole length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    None
+}
+
+pub fn generate_connect_html(urls: &[ServerUrl]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit - Connect</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; }
+        h1 { color: #333; }
+        .url-list { display: flex; flex-wrap: wrap; gap: 20px; }
+        .url-item {
+            width: 260px;
+            padding: 15px;
+            background-color: #f5f5f5;
+            border-radius: 5px;
+        }
+        .url-label { font-weight: bold; margin-bottom: 5px; }
+        .url-note { font-size: 0.9em; color: #666; margin-top: 5px; }
+        .url-item input { width: 190px; }
+        .url-item svg { display: block; width: 100%; margin-top: 10px; }
+    </style>
+</head>
+<body>
+    <h1>Connect</h1>
+    <p><a href="/">Back to the catalog</a></p>
+    <p>Open one of these on the device you want to watch on, or scan its code.</p>
+    <div class="url-list">
+"#,
+    );
+
+    if urls.is_empty() {
+        html.push_str("<p>No network addresses found.</p>");
+    }
+
+    for url in urls {
+        let escaped = http::html_escape(&url.url);
+        html.push_str(&format!(
+            r#"<div class="url-item"><div class="url-label">{}</div><input value="{}" readonly> <button onclick="copyUrl(this)">Copy</button>"#,
+            http::html_escape(&url.label),
+            escaped
+        ));
+        if let Some(note) = url.note {
+            html.push_str(&format!(r#"<div class="url-note">{}</div>"#, note));
+        }
+        if let Some(code) = qr::QrCode::encode(url.url.as_bytes()) {
+            html.push_str(&code.to_svg());
+        }
+        html.push_str("</div>");
+    }
+
+    html.push_str(
+        r#"</div>
+    <script>
+        function copyUrl(button) {
+            const input = button.previousElementSibling;
+            input.select();
+            // The clipboard API needs HTTPS or localhost
+            if (navigator.clipboard && window.isSecureContext) {
+                navigator.clipboard.writeText(input.value);
+            } else {
+                document.execCommand("copy");
+            }
+            button.textContent = "Copied";
+            setTimeout(() => { button.textContent = "Copy"; }, 2000);
+        }
+    </script>
+</body>
+</html>"#,
+    );
+    html
+}
@@ -0,0 +1,310 @@
+//! `streamshit doctor --network`: serves the library on a loopback port and
+//! measures it with a client on the same host, so a slow server can be told
+//! apart from a slow network.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::header::{COOKIE, HOST, RANGE};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+use crate::VideoEntry;
+use crate::session::{COOKIE_NAME, Role};
+use crate::state::AppState;
+use crate::transport::{Delivery, RouteClass};
+
+/// Most bytes read for the throughput measurement.
+const THROUGHPUT_SAMPLE: u64 = 128 * 1024 * 1024;
+
+/// Ranges requested for the latency measurement, and their size: what a
+/// player asks for when seeking.
+const RANGE_REQUESTS: u64 = 20;
+const RANGE_SIZE: u64 = 64 * 1024;
+
+/// How long ffmpeg gets to send its first bytes.
+#[cfg(feature = "transcoding")]
+const REMUX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gigabit Ethernet, the usual home network, in bytes per second. A server
+/// slower than this is the bottleneck rather than the network.
+const GIGABIT: f64 = 117_000_000.0;
+
+/// A 4K Blu-ray remux, about 80 Mbit/s, in bytes per second.
+const STREAM_4K: f64 = 10_000_000.0;
+
+/// Range latency an SSD is expected to stay under, and that a spinning disk
+/// still reaches. Seeking feels instant up to the latter.
+const RANGE_SSD: Duration = Duration::from_millis(10);
+const RANGE_HDD: Duration = Duration::from_millis(50);
+
+/// ffmpeg startup players wait for without noticing, and past which some
+/// give up.
+#[cfg(feature = "transcoding")]
+const REMUX_FAST: Duration = Duration::from_secs(1);
+#[cfg(feature = "transcoding")]
+const REMUX_SLOW: Duration = Duration::from_secs(3);
+
+/// Measures the server listening on `addr` and prints a tuning report.
+/// Fails if there is nothing to measure or the server can't be reached.
+pub async fn run(state: &AppState, addr: SocketAddr) -> Result<(), String> {
+    let Some((video, len)) = pick_video(&state.library.videos()) else {
+        return Err("no video files to measure".to_string());
+    };
+    let name = video
+        .path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    // Log in as an admin, so the measurements get past a required login
+    let cookie = state.auth.login_required().then(|| {
+        format!(
+            "{}={}",
+            COOKIE_NAME,
+            state.auth.sessions.issue("doctor", Role::Admin)
+        )
+    });
+    let client = Client { addr, cookie };
+    let path = format!("/{}", video.alias);
+
+    println!(
+        "Measuring {} ({:.1} MB) over {}",
+        name,
+        len as f64 / 1_000_000.0,
+        addr
+    );
+    println!();
+
+    let mut hints = Vec::new();
+    let strategy = state.config.transport.strategy(RouteClass::Video);
+
+    let sample = len.min(THROUGHPUT_SAMPLE);
+    let rate = client.throughput(&path, sample).await?;
+    println!(
+        "File throughput   {:>10}   {}; {} simultaneous 4K streams",
+        format!("{:.1} MB/s", rate / 1_000_000.0),
+        if rate >= GIGABIT {
+            "OK, faster than gigabit Ethernet (117 MB/s)"
+        } else {
+            "SLOW, the server can't fill gigabit Ethernet (117 MB/s)"
+        },
+        (rate / STREAM_4K) as u64
+    );
+    if rate < GIGABIT {
+        if let Some(limit) = strategy.rate_limit {
+            hints.push(format!(
+                "Videos are rate limited to {:.1} MB/s per response by the transport config.",
+                limit as f64 / 1_000_000.0
+            ));
+        } else if strategy.delivery == Delivery::Buffered {
+            hints.push(
+                "Buffered delivery reads each range whole before sending it; try `delivery = streamed` or `mmap` under [video]."
+                    .to_string(),
+            );
+        } else if strategy.chunk_size < 1024 * 1024 {
+            hints.push(format!(
+                "Try a larger `chunk_size` under [video] than {} KB.",
+                strategy.chunk_size / 1024
+            ));
+        }
+    }
+
+    let latencies = client.range_latencies(&path, len).await?;
+    let median = latencies[latencies.len() / 2];
+    let worst = latencies[latencies.len() - 1];
+    println!(
+        "Range latency     {:>10}   {} (worst {}, of {} seeks)",
+        format_duration(median),
+        if median <= RANGE_SSD {
+            "OK, as expected from an SSD (under 10 ms)"
+        } else if median <= RANGE_HDD {
+            "OK, as expected from a spinning disk (under 50 ms)"
+        } else {
+            "SLOW, seeking will stall (expected under 50 ms)"
+        },
+        format_duration(worst),
+        latencies.len()
+    );
+    if median > RANGE_HDD {
+        hints.push(
+            "The disk is slow to seek: a sleeping USB drive or a busy NAS can do this.".to_string(),
+        );
+    }
+
+    #[cfg(feature = "transcoding")]
+    match client.remux_startup(&path).await {
+        Ok(startup) => {
+            println!(
+                "Remux startup     {:>10}   {}",
+                format_duration(startup),
+                if startup <= REMUX_FAST {
+                    "OK, under 1 s"
+                } else if startup <= REMUX_SLOW {
+                    "SLOW, players wait over a second for episodes and MP4 fallbacks"
+                } else {
+                    "TOO SLOW, some players give up after 3 s"
+                }
+            );
+            if startup > REMUX_FAST {
+                hints.push(format!(
+                    "{} is slow to start; check the host's CPU load.",
+                    state.config.ffmpeg
+                ));
+            }
+        }
+        Err(err) => {
+            println!("Remux startup     {:>10}   FAILED, {}", "-", err);
+            hints.push(format!(
+                "Episodes, audio filters and the MP4 fallback need a working {} (--ffmpeg).",
+                state.config.ffmpeg
+            ));
+        }
+    }
+
+    println!();
+    if hints.is_empty() {
+        println!("Nothing to tune.");
+    }
+    for hint in hints {
+        println!("- {}", hint);
+    }
+    println!(
+        "The first run reads from the disk, later ones mostly from the page cache; run twice to tell them apart."
+    );
+    Ok(())
+}
+
+/// The largest whole file, which gives the throughput measurement the most
+/// to read.
+fn pick_video(videos: &[VideoEntry]) -> Option<(VideoEntry, u64)> {
+    videos
+        .iter()
+        .filter(|video| video.episode.is_none())
+        .filter_map(|video| Some((video.clone(), video.path.metadata().ok()?.len())))
+        .filter(|(_, len)| *len > 0)
+        .max_by_key(|(_, len)| *len)
+}
+
+struct Client {
+    addr: SocketAddr,
+    cookie: Option<String>,
+}
+
+impl Client {
+    async fn connect(&self) -> Result<SendRequest<Empty<Bytes>>, String> {
+        let stream = TcpStream::connect(self.addr)
+            .await
+            .map_err(|e| format!("could not connect to {}: {}", self.addr, e))?;
+        let (sender, connection) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| format!("HTTP handshake failed: {}", e))?;
+        tokio::spawn(connection);
+        Ok(sender)
+    }
+
+    /// Sends a GET for `path`, returning once the response head arrived.
+    async fn get(
+        &self,
+        sender: &mut SendRequest<Empty<Bytes>>,
+        path: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Response<Incoming>, String> {
+        let mut req = Request::builder().uri(path).header(HOST, "localhost");
+        if let Some(cookie) = &self.cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        if let Some((start, end)) = range {
+            req = req.header(RANGE, format!("bytes={}-{}", start, end));
+        }
+        // The connection is kept alive between seeks, once done with the last
+        sender
+            .ready()
+            .await
+            .map_err(|e| format!("the connection closed: {}", e))?;
+        let response = sender
+            .send_request(req.body(Empty::new()).unwrap())
+            .await
+            .map_err(|e| format!("request for {} failed: {}", path, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", path, response.status()));
+        }
+        Ok(response)
+    }
+
+    /// Bytes per second reading the first `len` bytes of `path`.
+    async fn throughput(&self, path: &str, len: u64) -> Result<f64, String> {
+        let mut sender = self.connect().await?;
+        let started = Instant::now();
+        let response = self.get(&mut sender, path, Some((0, len - 1))).await?;
+        let read = read_body(response).await?;
+        Ok(read as f64 / started.elapsed().as_secs_f64())
+    }
+
+    /// The time each of a series of seeks spread over the file took, from
+    /// sending the request to the last byte, sorted.
+    async fn range_latencies(&self, path: &str, len: u64) -> Result<Vec<Duration>, String> {
+        let mut sender = self.connect().await?;
+        let size = len.min(RANGE_SIZE);
+        let mut latencies = Vec::new();
+        for i in 0..RANGE_REQUESTS {
+            let start = (len - size) * i / (RANGE_REQUESTS - 1);
+            let started = Instant::now();
+            let response = self
+                .get(&mut sender, path, Some((start, start + size - 1)))
+                .await?;
+            read_body(response).await?;
+            latencies.push(started.elapsed());
+        }
+        latencies.sort();
+        Ok(latencies)
+    }
+
+    /// How long ffmpeg takes to send the first bytes of `path` rewrapped
+    /// as MP4.
+    #[cfg(feature = "transcoding")]
+    async fn remux_startup(&self, path: &str) -> Result<Duration, String> {
+        let mut sender = self.connect().await?;
+        let path = format!("{}?{}=mp4", path, crate::remux::FORMAT_PARAM);
+        let started = Instant::now();
+        let mut body = self.get(&mut sender, &path, None).await?.into_body();
+        let first_data = async {
+            while let Some(frame) = body.frame().await {
+                let frame = frame.map_err(|e| format!("reading the response failed: {}", e))?;
+                if frame.data_ref().is_some_and(|data| !data.is_empty()) {
+                    return Ok(started.elapsed());
+                }
+            }
+            Err("ffmpeg sent nothing".to_string())
+        };
+        // Dropping the connection stops ffmpeg
+        tokio::time::timeout(REMUX_TIMEOUT, first_data)
+            .await
+            .map_err(|_| "ffmpeg sent nothing within 10 s".to_string())?
+    }
+}
+
+/// Reads a whole body, returning its length.
+async fn read_body(response: Response<Incoming>) -> Result<u64, String> {
+    let mut body = response.into_body();
+    let mut read = 0;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| format!("reading the response failed: {}", e))?;
+        if let Some(data) = frame.data_ref() {
+            read += data.len() as u64;
+        }
+    }
+    Ok(read)
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+    } else {
+        format!("{:.2} s", duration.as_secs_f64())
+    }
+}
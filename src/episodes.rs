@@ -0,0 +1,72 @@
+//! Splits files holding several episodes, like discs ripped as one file,
+//! into one virtual catalog entry per chapter.
+
+use std::time::Duration;
+
+#[cfg(feature = "transcoding")]
+use crate::{VideoEntry, probe};
+
+/// Chapters shorter than this are scenes of a movie rather than episodes,
+/// so files with any such chapter are left whole.
+#[cfg(feature = "transcoding")]
+const MIN_EPISODE: Duration = Duration::from_secs(10 * 60);
+
+/// A time range of a file.
+#[derive(Clone)]
+pub struct Clip {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// A chapter of another entry, served through the remuxer.
+#[derive(Clone)]
+pub struct Episode {
+    /// Alias of the entry holding the whole file.
+    pub of: String,
+    pub title: String,
+    pub clip: Clip,
+}
+
+/// Appends an entry for every episode of the titles in `videos`. Episode
+/// aliases are `<number of the file>-<episode number>.mkv`.
+#[cfg(feature = "transcoding")]
+pub fn split(videos: &mut Vec<VideoEntry>) {
+    let mut episodes = Vec::new();
+
+    for video in videos.iter().filter(|video| video.extra_of.is_none()) {
+        let chapters = probe::chapters(&video.path);
+        if chapters.len() < 2
+            || chapters
+                .iter()
+                .any(|chapter| chapter.end - chapter.start < MIN_EPISODE)
+        {
+            continue;
+        }
+
+        let number = video.alias.split('.').next().unwrap_or_default();
+        for (i, chapter) in chapters.into_iter().enumerate() {
+            let title = if chapter.title.is_empty() {
+                format!("Episode {}", i + 1)
+            } else {
+                chapter.title
+            };
+            episodes.push(VideoEntry {
+                path: video.path.clone(),
+                alias: format!("{}-{}.mkv", number, i + 1),
+                problem: None,
+                duration: Some(chapter.end - chapter.start),
+                extra_of: None,
+                episode: Some(Episode {
+                    of: video.alias.clone(),
+                    title,
+                    clip: Clip {
+                        start: chapter.start,
+                        end: chapter.end,
+                    },
+                }),
+            });
+        }
+    }
+
+    videos.extend(episodes);
+}
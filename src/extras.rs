@@ -0,0 +1,91 @@
+//! Recognizes trailers, samples and similar extras so the catalog can list
+//! them under their main title instead of as titles of their own.
+
+use std::time::Duration;
+
+use crate::VideoEntry;
+
+/// Name words marking a file as an extra.
+const EXTRA_WORDS: [&str; 5] = ["trailer", "sample", "teaser", "preview", "promo"];
+
+/// Files named like an extra but running longer than this are kept as titles
+/// ("Trailer Park Boys S01E01" is not a trailer).
+const MAX_NAMED_EXTRA: Duration = Duration::from_secs(15 * 60);
+
+/// Unnamed files this short that share their name with a longer title are
+/// taken to be clips of it.
+const MAX_UNNAMED_EXTRA: Duration = Duration::from_secs(3 * 60);
+
+/// Points every extra in `videos` at its main title through `extra_of`.
+/// Extras whose main title is not in the list stay standalone.
+pub fn attach(videos: &mut [VideoEntry]) {
+    let infos: Vec<Info> = videos.iter().map(Info::of).collect();
+
+    for (i, info) in infos.iter().enumerate() {
+        let Some(title) = info.extra_title() else {
+            continue;
+        };
+        // The standalone title whose name is closest to this one, where one
+        // name starts with the other ("Movie (2020)", "Movie 2020 1080p")
+        let main = infos
+            .iter()
+            .enumerate()
+            .filter(|(j, other)| {
+                *j != i
+                    && other.extra_title().is_none()
+                    && !other.words.is_empty()
+                    && (other.words.starts_with(title) || title.starts_with(&other.words))
+            })
+            .min_by_key(|(_, other)| other.words.len().abs_diff(title.len()))
+            .map(|(j, _)| j);
+
+        if let Some(main) = main {
+            videos[i].extra_of = Some(videos[main].alias.clone());
+        }
+    }
+}
+
+struct Info {
+    /// Lowercase alphanumeric words of the file name, without the extension.
+    words: Vec<String>,
+    /// Index of the first extra word, if the name has one.
+    extra_word: Option<usize>,
+    duration: Option<Duration>,
+}
+
+impl Info {
+    fn of(video: &VideoEntry) -> Info {
+        let stem = video
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let words: Vec<String> = stem
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_string)
+            .collect();
+        let extra_word = words
+            .iter()
+            .position(|word| EXTRA_WORDS.contains(&word.as_str()));
+
+        Info {
+            words,
+            extra_word,
+            duration: video.duration,
+        }
+    }
+
+    /// The words naming the main title, if this file looks like an extra.
+    fn extra_title(&self) -> Option<&[String]> {
+        let title = match self.extra_word {
+            Some(position) if self.duration.is_none_or(|d| d <= MAX_NAMED_EXTRA) => {
+                &self.words[..position]
+            }
+            None if self.duration.is_some_and(|d| d <= MAX_UNNAMED_EXTRA) => &self.words[..],
+            _ => return None,
+        };
+        (!title.is_empty()).then_some(title)
+    }
+}
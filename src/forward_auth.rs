@@ -0,0 +1,56 @@
+use std::net::IpAddr;
+
+use hyper::Request;
+use ipnet::IpNet;
+
+use crate::session::{Role, Session};
+
+/// Trusts the user identity asserted by a reverse proxy that already handled
+/// login (Authelia, Authentik or Traefik forward-auth).
+///
+/// The identity headers are only honored on connections coming from one of
+/// the trusted proxy networks; anyone else could simply set them.
+pub struct ForwardAuth {
+    pub trusted_proxies: Vec<IpNet>,
+    pub user_headers: Vec<String>,
+    pub groups_header: String,
+    pub admin_groups: Vec<String>,
+}
+
+impl ForwardAuth {
+    fn is_trusted(&self, peer: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&peer))
+    }
+
+    pub fn authenticate<B>(&self, peer: IpAddr, req: &Request<B>) -> Option<Session> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+
+        let user = self
+            .user_headers
+            .iter()
+            .filter_map(|name| req.headers().get(name.as_str()))
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .find(|value| !value.is_empty())?;
+
+        let is_admin = req
+            .headers()
+            .get(self.groups_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(|groups| {
+                groups
+                    .split(',')
+                    .map(str::trim)
+                    .any(|group| self.admin_groups.iter().any(|admin| admin == group))
+            })
+            .unwrap_or(false);
+
+        Some(Session {
+            user: user.to_string(),
+            role: if is_admin { Role::Admin } else { Role::Viewer },
+            videos: None,
+        })
+    }
+}
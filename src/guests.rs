@@ -0,0 +1,222 @@
+//! Guest accounts: temporary, view-only logins an admin hands out as links,
+//! for visitors who shouldn't get the password.
+
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+use crate::crypto;
+use crate::http;
+use crate::patterns::VideoPatterns;
+
+#[derive(Clone)]
+pub struct Guest {
+    pub id: String,
+    pub name: String,
+    pub videos: VideoPatterns,
+    /// Unix time the account stops working.
+    pub expires: u64,
+    hash: [u8; 32],
+}
+
+impl Guest {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+}
+
+/// Temporary accounts for visitors, handed out as links by an admin.
+///
+/// Opening a link logs the browser in as the guest, who may watch the
+/// videos matching the guest's patterns until the account expires or is
+/// revoked. A token looks like `<id>_<secret>`; only its SHA-256 is kept,
+/// so the link is shown once when it is created. Guests only live until the
+/// server stops.
+#[derive(Default)]
+pub struct Guests {
+    guests: RwLock<Vec<Guest>>,
+}
+
+impl Guests {
+    /// The guests that haven't expired yet.
+    pub fn list(&self) -> Vec<Guest> {
+        let mut guests = self.guests.write().unwrap();
+        guests.retain(|guest| !guest.is_expired());
+        guests.clone()
+    }
+
+    /// Creates a guest for `ttl`, returning it and its token, or `None` if
+    /// the expiry is too far off to represent.
+    pub fn create(
+        &self,
+        name: &str,
+        videos: VideoPatterns,
+        ttl: Duration,
+    ) -> Option<(Guest, String)> {
+        let expires = now().checked_add(ttl.as_secs())?;
+        let id = crypto::to_hex(&crypto::random_bytes(4));
+        let token = format!(
+            "{}_{}",
+            id,
+            URL_SAFE_NO_PAD.encode(crypto::random_bytes(24))
+        );
+        let guest = Guest {
+            id,
+            name: name.to_string(),
+            videos,
+            expires,
+            hash: crypto::sha256(token.as_bytes()),
+        };
+        self.guests.write().unwrap().push(guest.clone());
+        Some((guest, token))
+    }
+
+    /// Revokes the guest with `id`, returning whether it existed.
+    pub fn revoke(&self, id: &str) -> bool {
+        let mut guests = self.guests.write().unwrap();
+        let before = guests.len();
+        guests.retain(|guest| guest.id != id);
+        guests.len() != before
+    }
+
+    /// The guest a link's token belongs to, if it is valid.
+    pub fn redeem(&self, token: &str) -> Option<Guest> {
+        let (id, _) = token.split_once('_')?;
+        let hash = crypto::sha256(token.as_bytes());
+        self.get(id)
+            .filter(|guest| crypto::constant_time_eq(&guest.hash, &hash))
+    }
+
+    /// The unexpired guest with `id`.
+    pub fn get(&self, id: &str) -> Option<Guest> {
+        self.guests
+            .read()
+            .unwrap()
+            .iter()
+            .find(|guest| guest.id == id && !guest.is_expired())
+            .cloned()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The admin page listing guests, with a form creating a guest link.
+pub fn generate_guests_html(guests: &[Guest]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit - Guest Links</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; }
+        h1 { color: #333; }
+        .guest-form {
+            background-color: #f5f5f5;
+            padding: 15px;
+            border-radius: 5px;
+            max-width: 420px;
+            margin-bottom: 20px;
+        }
+        .guest-form input, .guest-form select { padding: 8px; margin: 5px 0; width: 100%; box-sizing: border-box; }
+        .guest-link { word-break: break-all; margin-top: 10px; }
+        .guest { margin: 10px 0; }
+        .guest-name { font-weight: bold; }
+        .guest-detail { font-size: 0.9em; color: #666; }
+    </style>
+</head>
+<body>
+    <h1>Guest Links</h1>
+    <p><a href="/">Back to the catalog</a></p>
+    <form class="guest-form" id="guest-form">
+        <input name="name" placeholder="Name (for you)">
+        <input name="videos" placeholder="Videos, e.g. Movie*, *cartoon*" required>
+        <select name="duration">
+            <option value="10800">3 hours</option>
+            <option value="86400" selected>1 day</option>
+            <option value="259200">3 days</option>
+            <option value="604800">1 week</option>
+        </select>
+        <input type="submit" value="Create link">
+        <div class="guest-link" id="guest-link"></div>
+    </form>
+"#,
+    );
+
+    if guests.is_empty() {
+        html.push_str("<p>No guest links are active.</p>");
+    }
+    let now = now();
+    for guest in guests {
+        let hours = guest.expires.saturating_sub(now).div_ceil(3600);
+        html.push_str(&format!(
+            r#"<div class="guest"><span class="guest-name">{}</span> <button data-id="{}">Revoke</button><div class="guest-detail">{} &middot; expires in {} hour{}</div></div>"#,
+            http::html_escape(&guest.name),
+            http::html_escape(&guest.id),
+            http::html_escape(&guest.videos.as_slice().join(", ")),
+            hours,
+            if hours == 1 { "" } else { "s" }
+        ));
+    }
+
+    html.push_str(
+        r#"<script>
+        const form = document.getElementById('guest-form');
+        form.addEventListener('submit', async (event) => {
+            event.preventDefault();
+            const output = document.getElementById('guest-link');
+            const response = await fetch('/api/guests', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({
+                    name: form.elements.namedItem('name').value,
+                    videos: form.elements.namedItem('videos').value.split(',').map((v) => v.trim()).filter((v) => v),
+                    duration: Number(form.elements.namedItem('duration').value),
+                }),
+            });
+            const guest = await response.json();
+            output.textContent = response.ok
+                ? 'Send this link, it is only shown once: ' + guest.link
+                : guest.error;
+        });
+        document.querySelectorAll('button[data-id]').forEach((button) => {
+            button.addEventListener('click', async () => {
+                await fetch('/api/guests/' + encodeURIComponent(button.dataset.id), { method: 'DELETE' });
+                location.reload();
+            });
+        });
+    </script>
+</body>
+</html>"#,
+    );
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_an_expiry_too_far_off() {
+        let guests = Guests::default();
+        let videos = VideoPatterns::new(vec!["Movie*".to_string()]);
+        assert!(
+            guests
+                .create("Sam", videos.clone(), Duration::MAX)
+                .is_none()
+        );
+        assert!(guests.list().is_empty());
+
+        let (guest, token) = guests
+            .create("Sam", videos, Duration::from_secs(3600))
+            .unwrap();
+        assert!(guest.expires >= now() + 3599 && guest.expires <= now() + 3600);
+        assert_eq!(guests.redeem(&token).unwrap().id, guest.id);
+    }
+}
@@ -0,0 +1,127 @@
+//! The kid profile: a browser switched to it sees only the videos whose file
+//! names match the allowed patterns, as big tiles without admin links or
+//! settings. Switching needs no PIN, it only keeps kids to their corner.
+
+use hyper::Request;
+
+use crate::patterns::VideoPatterns;
+use crate::{VideoEntry, http};
+
+pub const COOKIE_NAME: &str = "streamshit_profile";
+
+/// Routes switching the profile on and off.
+pub const ENTER_PATH: &str = "/kids";
+pub const LEAVE_PATH: &str = "/kids/leave";
+
+/// The videos kids may watch. The profile is off without any patterns.
+pub struct KidProfile {
+    videos: VideoPatterns,
+}
+
+impl KidProfile {
+    pub fn new(patterns: Vec<String>) -> Self {
+        KidProfile {
+            videos: VideoPatterns::new(patterns),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.videos.is_empty()
+    }
+
+    /// Whether the request comes from a browser switched to the profile.
+    pub fn is_active<B>(&self, req: &Request<B>) -> bool {
+        self.is_enabled() && http::cookie(req, COOKIE_NAME).as_deref() == Some("kid")
+    }
+
+    pub fn allows(&self, video: &VideoEntry) -> bool {
+        self.videos.matches(video)
+    }
+}
+
+/// Sets or clears the profile cookie.
+pub fn set_cookie(active: bool) -> String {
+    if active {
+        format!(
+            "{}=kid; Path=/; SameSite=Lax; Max-Age=31536000",
+            COOKIE_NAME
+        )
+    } else {
+        format!("{}=; Path=/; Max-Age=0", COOKIE_NAME)
+    }
+}
+
+/// The catalog as kids see it: a tile per title, or per episode for files
+/// split into episodes.
+pub fn generate_catalog_html(videos: &[VideoEntry]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit</title>
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <style>
+        body { font-family: Arial, sans-serif; margin: 20px; background-color: #fdf6e3; }
+        .tiles { display: grid; grid-template-columns: repeat(auto-fill, minmax(220px, 1fr)); gap: 20px; }
+        .tile {
+            display: flex;
+            align-items: center;
+            justify-content: center;
+            min-height: 160px;
+            padding: 20px;
+            border-radius: 20px;
+            background-color: #4a90d9;
+            color: #fff;
+            font-size: 1.6em;
+            font-weight: bold;
+            text-align: center;
+            text-decoration: none;
+        }
+        .tile:nth-child(3n+2) { background-color: #e5804a; }
+        .tile:nth-child(3n) { background-color: #5cb85c; }
+        .leave { display: block; margin-top: 40px; color: #999; font-size: 0.8em; }
+    </style>
+</head>
+<body>
+    <div class="tiles">
+"#,
+    );
+
+    let split: Vec<&str> = videos
+        .iter()
+        .filter_map(|video| video.episode.as_ref().map(|episode| episode.of.as_str()))
+        .collect();
+    let mut tiles = 0;
+    for video in videos
+        .iter()
+        .filter(|video| video.extra_of.is_none() && !split.contains(&video.alias.as_str()))
+    {
+        let stem = video
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        let title = match &video.episode {
+            Some(episode) => format!("{}: {}", stem, episode.title),
+            None => stem.to_string(),
+        };
+        html.push_str(&format!(
+            r#"<a class="tile" href="/watch/{}">{}</a>"#,
+            http::percent_encode(&video.alias),
+            http::html_escape(&title)
+        ));
+        tiles += 1;
+    }
+    if tiles == 0 {
+        html.push_str("<p>Nothing to watch here yet.</p>");
+    }
+
+    html.push_str(&format!(
+        r#"</div>
+    <a class="leave" href="{}">Leave kid profile</a>
+</body>
+</html>"#,
+        LEAVE_PATH
+    ));
+    html
+}
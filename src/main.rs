@@ -1,22 +1,90 @@
+mod analytics;
+mod api;
+mod api_keys;
+mod auth;
+mod check;
+mod connect;
+mod crypto;
+mod doctor;
+mod episodes;
+mod extras;
+mod forward_auth;
+mod guests;
+mod gzip;
+mod http;
+mod idle;
+mod json;
+mod kids;
+mod maintenance;
+mod middleware;
+mod oidc;
+mod patterns;
+mod player;
+mod probe;
+mod qr;
+#[cfg(feature = "transcoding")]
+mod remux;
+mod routes;
+mod session;
+mod state;
+mod storage;
+#[cfg(test)]
+mod testutil;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+
 use std::convert::Infallible;
 use std::fs;
-use std::net::{SocketAddr, UdpSocket};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
-use http_body_util::Full;
+use clap::{Parser, Subcommand};
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::body::{Bytes, Incoming};
+use hyper::header::{HOST, LOCATION, RANGE, SET_COOKIE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
 use tokio::net::TcpListener;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use analytics::Analytics;
+use api_keys::ApiKeys;
+use auth::Auth;
+use connect::Addresses;
+use episodes::Episode;
+use forward_auth::ForwardAuth;
+use guests::Guests;
+use kids::KidProfile;
+use maintenance::Maintenance;
+use middleware::{
+    AccessLog, Authentication, Caller, Compression, Connection, MaintenanceGate, MiddlewareLayer,
+    RateLimit, Resolved, Routing, ServiceFn, Timeout,
+};
+use oidc::{Oidc, OidcConfig};
+#[cfg(feature = "transcoding")]
+use remux::{AudioFilter, Remux};
+use routes::Route;
+use session::{Role, Session, Sessions};
+use state::{AppState, Config, Library};
+use storage::DiskMonitor;
+use transport::{Delivery, Strategy, TransportConfig};
 
 #[derive(Parser)]
 #[command(name = "streamshit")]
 #[command(about = "A simple video streaming server")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Port to listen on
     #[arg(short, long, default_value = "6969")]
     port: u16,
@@ -26,35 +94,234 @@ struct Args {
     host: String,
 
     /// Directory containing video files
-    #[arg(short, long, default_value = ".")]
+    #[arg(short, long, default_value = ".", global = true)]
     video_dir: String,
+
+    /// Require this password to log in before browsing or streaming
+    #[arg(long, env = "STREAMSHIT_PASSWORD")]
+    password: Option<String>,
+
+    /// Secret used to sign session cookies (random per start if unset,
+    /// which logs every device out on restart)
+    #[arg(long, env = "STREAMSHIT_SESSION_SECRET")]
+    session_secret: Option<String>,
+
+    /// Previously used session secrets that are still accepted, for rotation
+    #[arg(
+        long,
+        env = "STREAMSHIT_PREVIOUS_SESSION_SECRETS",
+        value_delimiter = ','
+    )]
+    previous_session_secret: Vec<String>,
+
+    /// How long a login stays valid, in hours
+    #[arg(long, default_value = "720")]
+    session_ttl: u64,
+
+    /// OpenID Connect issuer URL, enabling single sign-on (https:// needs
+    /// the tls feature)
+    #[arg(long, env = "STREAMSHIT_OIDC_ISSUER")]
+    oidc_issuer: Option<String>,
+
+    /// OpenID Connect client ID
+    #[arg(long, env = "STREAMSHIT_OIDC_CLIENT_ID", requires = "oidc_issuer")]
+    oidc_client_id: Option<String>,
+
+    /// OpenID Connect client secret
+    #[arg(long, env = "STREAMSHIT_OIDC_CLIENT_SECRET", requires = "oidc_issuer")]
+    oidc_client_secret: Option<String>,
+
+    /// Callback URL registered with the provider (defaults to
+    /// /auth/callback on the Host the browser connected to, over https when
+    /// serving TLS)
+    #[arg(long)]
+    oidc_redirect_url: Option<String>,
+
+    /// Claim holding the user's groups or roles
+    #[arg(long, default_value = "groups")]
+    oidc_role_claim: String,
+
+    /// Claim values granting the admin role
+    #[arg(long, value_delimiter = ',')]
+    oidc_admin_group: Vec<String>,
+
+    /// Claim values allowed to watch; if unset, any authenticated user may
+    #[arg(long, value_delimiter = ',')]
+    oidc_viewer_group: Vec<String>,
+
+    /// CA certificate (PEM) to verify an https:// issuer with, instead of
+    /// the system's CAs
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "oidc_issuer")]
+    oidc_ca: Option<PathBuf>,
+
+    /// Reverse proxy address or network (CIDR) whose forward-auth user
+    /// headers are trusted
+    #[arg(long, value_delimiter = ',', value_parser = parse_ip_net)]
+    trusted_proxy: Vec<IpNet>,
+
+    /// Headers carrying the user name set by the trusted proxy
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "Remote-User,X-Forwarded-User"
+    )]
+    forward_auth_user_header: Vec<String>,
+
+    /// Header carrying the user's comma separated groups
+    #[arg(long, default_value = "Remote-Groups")]
+    forward_auth_groups_header: String,
+
+    /// Groups granting the admin role to forward-authenticated users
+    #[arg(long, value_delimiter = ',')]
+    forward_auth_admin_group: Vec<String>,
+
+    /// File storing API keys (kept in memory only if unset)
+    #[arg(long)]
+    api_keys_file: Option<PathBuf>,
+
+    /// File mapping route classes (catalog, video, api) to compression,
+    /// delivery, rate limit and chunk size settings
+    #[arg(long)]
+    transport_config: Option<PathBuf>,
+
+    /// File storing which parts of each video get watched in the browser
+    /// player (kept in memory only if unset)
+    #[arg(long)]
+    analytics_file: Option<PathBuf>,
+
+    /// PEM certificate chain to serve HTTPS with
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Only accept clients presenting a certificate signed by this CA (PEM)
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// URL the server is also reachable at, such as a tunnel or reverse
+    /// proxy, listed first on /connect
+    #[arg(long, env = "STREAMSHIT_PUBLIC_URL")]
+    public_url: Option<String>,
+
+    /// File name pattern (`*` wildcards) of videos for the kid profile,
+    /// which browsers can switch to from the catalog; repeatable
+    #[arg(long)]
+    kid_video: Vec<String>,
+
+    /// Log every request (client, method, path, status, time taken)
+    #[arg(long)]
+    access_log: bool,
+
+    /// Seconds a response may take to start before the request is answered
+    /// with 503
+    #[arg(long, default_value_t = 30)]
+    request_timeout: u64,
+
+    /// Seconds after which connections that moved no data are closed, such
+    /// as streams left open by a paused player (0 to keep them)
+    #[arg(long, default_value_t = 600)]
+    idle_timeout: u64,
+
+    /// ffmpeg binary used to serve episodes and by `check --full`
+    #[cfg(feature = "transcoding")]
+    #[arg(long, default_value = "ffmpeg", global = true)]
+    ffmpeg: String,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check every video for container corruption and flag broken files in
+    /// the catalog
+    Check {
+        /// Also decode every file completely with ffmpeg (slow)
+        #[cfg(feature = "transcoding")]
+        #[arg(long)]
+        full: bool,
+    },
+    /// Diagnose how well this host serves videos
+    Doctor {
+        /// Serve the library on a loopback port and measure file throughput,
+        /// seek latency and ffmpeg startup against typical hardware
+        #[arg(long)]
+        network: bool,
+    },
+}
+
+/// Accepts either a network in CIDR notation or a single address.
+fn parse_ip_net(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is not an IP address or network", value))
 }
 
 #[derive(Clone)]
 struct VideoEntry {
     path: PathBuf,
     alias: String,
-}
-
-fn get_local_ip() -> Result<String, Box<dyn std::error::Error>> {
-    // Connect to a remote address to determine local IP
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect("8.8.8.8:80")?;
-    let local_addr = socket.local_addr()?;
-    Ok(local_addr.ip().to_string())
+    /// Problem found by the last `streamshit check`, if any.
+    problem: Option<String>,
+    /// Running time, probed when the library is scanned.
+    duration: Option<Duration>,
+    /// Alias of the main title, if this is a trailer, sample or other extra.
+    extra_of: Option<String>,
+    /// Set on the virtual entries for the chapters of multi-episode files.
+    episode: Option<Episode>,
 }
 
 async fn list_videos_handler(
-    video_list: Arc<Vec<VideoEntry>>,
+    state: &AppState,
     server_url: Arc<String>,
-    _req: Request<Incoming>,
+    session: Option<Session>,
+    #[cfg_attr(not(feature = "transcoding"), allow(unused_variables))] req: Request<Incoming>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let html = generate_video_list_html(&video_list, &server_url);
+    #[cfg(feature = "transcoding")]
+    let audio_filter = AudioFilter::of(&req);
+    #[cfg(feature = "transcoding")]
+    let (filter_form, query) = (audio_filter.generate_form_html(), audio_filter.query());
+    #[cfg(not(feature = "transcoding"))]
+    let (filter_form, query) = (String::new(), String::new());
+    // Guests only see what they may watch
+    let videos: Vec<VideoEntry> = state
+        .library
+        .videos()
+        .iter()
+        .filter(|video| session.as_ref().is_none_or(|s| s.allows(video)))
+        .cloned()
+        .collect();
+    let html = generate_video_list_html(
+        &videos,
+        &server_url,
+        session.as_ref(),
+        &filter_form,
+        &query,
+        &state.disk.warnings(),
+        state.config.kids.is_enabled(),
+    );
 
-    let response = Response::builder()
-        .header("Content-Type", "text/html; charset=utf-8")
-        .body(Full::new(Bytes::from(html)))
-        .unwrap();
+    let response = Response::builder().header("Content-Type", "text/html; charset=utf-8");
+    // Choosing a filter on the catalog remembers it for this browser
+    #[cfg(feature = "transcoding")]
+    let response = if http::parse_query(&req).contains_key(remux::AUDIO_FILTER_PARAM) {
+        response.header(
+            SET_COOKIE,
+            format!(
+                "{}={}; Path=/; SameSite=Lax; Max-Age=31536000",
+                remux::AUDIO_FILTER_COOKIE,
+                audio_filter.as_param()
+            ),
+        )
+    } else {
+        response
+    };
+    let response = response.body(Full::new(Bytes::from(html))).unwrap();
 
     Ok(response)
 }
@@ -66,20 +333,19 @@ fn get_video_list(path: &str) -> Vec<VideoEntry> {
     if let Ok(entries) = fs::read_dir(path) {
         for entry in entries.flatten() {
             let path = entry.path();
-            if path.is_file() {
-                if let Some(extension) = path.extension() {
-                    if let Some(ext_str) = extension.to_str() {
-                        if video_extensions.contains(&ext_str.to_lowercase().as_str()) {
-                            video_paths.push(path);
-                        }
-                    }
-                }
+            if path.is_file()
+                && let Some(extension) = path.extension()
+                && let Some(ext_str) = extension.to_str()
+                && video_extensions.contains(&ext_str.to_lowercase().as_str())
+            {
+                video_paths.push(path);
             }
         }
     }
     video_paths.sort();
+    let mut problems = check::load_report(path);
 
-    video_paths
+    let mut videos: Vec<VideoEntry> = video_paths
         .into_iter()
         .enumerate()
         .map(|(i, path)| {
@@ -89,12 +355,233 @@ fn get_video_list(path: &str) -> Vec<VideoEntry> {
                 .to_str()
                 .unwrap_or_default();
             let alias = format!("{}.{}", i + 1, extension);
-            VideoEntry { path, alias }
+            let problem = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| problems.remove(name));
+            let duration = probe::duration(&path);
+            VideoEntry {
+                path,
+                alias,
+                problem,
+                duration,
+                extra_of: None,
+                episode: None,
+            }
         })
-        .collect()
+        .collect();
+    extras::attach(&mut videos);
+    #[cfg(feature = "transcoding")]
+    episodes::split(&mut videos);
+    videos
 }
 
-fn generate_video_list_html(videos: &[VideoEntry], server_url: &str) -> String {
+async fn login_handler(
+    sessions: &Sessions,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let body = match Limited::new(req.into_body(), 16 * 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => {
+            return login_page(
+                sessions,
+                StatusCode::BAD_REQUEST,
+                Some("Invalid login request."),
+            );
+        }
+    };
+    let form = http::parse_form(&String::from_utf8_lossy(&body));
+    let password = form.get("password").map(String::as_str).unwrap_or_default();
+
+    if !sessions.check_password(password) {
+        return login_page(sessions, StatusCode::UNAUTHORIZED, Some("Wrong password."));
+    }
+
+    let cookie = sessions.issue("admin", Role::Admin);
+    let response = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .header(SET_COOKIE, sessions.set_cookie(&cookie))
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    Ok(response)
+}
+
+/// Logs the browser in as the guest a link was made for, until the guest
+/// expires.
+fn guest_login_handler(auth: &Auth, token: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let Some(guest) = auth.guests.redeem(token) else {
+        if auth.sessions.is_enabled() {
+            return login_page(
+                &auth.sessions,
+                StatusCode::FORBIDDEN,
+                Some("This guest link has expired."),
+            );
+        }
+        return forbidden();
+    };
+
+    let cookie = auth
+        .sessions
+        .issue_until(&guest.id, Role::Guest, guest.expires);
+    let response = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .header(
+            SET_COOKIE,
+            auth.sessions.set_cookie_until(&cookie, guest.expires),
+        )
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    Ok(response)
+}
+
+async fn sso_login_handler(
+    sessions: &Sessions,
+    oidc: &Oidc,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let redirect_url = oidc.redirect_url(request_host(&req));
+
+    match oidc.authorization_url(&redirect_url).await {
+        Ok((state, url)) => {
+            let response = Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, url)
+                .header(
+                    SET_COOKIE,
+                    format!(
+                        "{}={}; Path=/auth; HttpOnly; SameSite=Lax; Max-Age=600{}",
+                        oidc::STATE_COOKIE_NAME,
+                        state,
+                        sessions.secure_flag()
+                    ),
+                )
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            Ok(response)
+        }
+        Err(err) => {
+            eprintln!("OIDC login failed: {}", err);
+            login_page(
+                sessions,
+                StatusCode::BAD_GATEWAY,
+                Some("The identity provider is unavailable."),
+            )
+        }
+    }
+}
+
+async fn sso_callback_handler(
+    sessions: &Sessions,
+    oidc: &Oidc,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let query = http::parse_query(&req);
+    let expected_state = http::cookie(&req, oidc::STATE_COOKIE_NAME).unwrap_or_default();
+    let state = query.get("state").map(String::as_str).unwrap_or_default();
+
+    if expected_state.is_empty()
+        || !crypto::constant_time_eq(expected_state.as_bytes(), state.as_bytes())
+    {
+        return login_page(
+            sessions,
+            StatusCode::BAD_REQUEST,
+            Some("Login expired, please try again."),
+        );
+    }
+    let Some(code) = query.get("code") else {
+        let error = query.get("error").map(String::as_str).unwrap_or("no code");
+        return login_page(
+            sessions,
+            StatusCode::UNAUTHORIZED,
+            Some(&format!("Single sign-on failed: {}", error)),
+        );
+    };
+
+    let redirect_url = oidc.redirect_url(request_host(&req));
+    match oidc.exchange(code, &redirect_url).await {
+        Ok(identity) => {
+            let cookie = sessions.issue(&identity.user, identity.role);
+            let response = Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, "/")
+                .header(SET_COOKIE, sessions.set_cookie(&cookie))
+                .header(
+                    SET_COOKIE,
+                    format!(
+                        "{}=; Path=/auth; Max-Age=0{}",
+                        oidc::STATE_COOKIE_NAME,
+                        sessions.secure_flag()
+                    ),
+                )
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            Ok(response)
+        }
+        Err(err) => {
+            eprintln!("OIDC callback failed: {}", err);
+            login_page(
+                sessions,
+                StatusCode::UNAUTHORIZED,
+                Some("Single sign-on failed."),
+            )
+        }
+    }
+}
+
+fn request_host<B>(req: &Request<B>) -> &str {
+    req.headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost")
+}
+
+fn logout_handler(sessions: &Sessions) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/login")
+        .header(SET_COOKIE, sessions.clear_cookie())
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    Ok(response)
+}
+
+/// Switches the browser to or from the kid profile, back on the catalog.
+fn kid_profile_handler(active: bool) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .header(SET_COOKIE, kids::set_cookie(active))
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    Ok(response)
+}
+
+fn login_page(
+    sessions: &Sessions,
+    status: StatusCode,
+    error: Option<&str>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Full::new(Bytes::from(session::generate_login_html(
+            sessions, error,
+        ))))
+        .unwrap();
+    Ok(response)
+}
+
+fn generate_video_list_html(
+    videos: &[VideoEntry],
+    server_url: &str,
+    session: Option<&Session>,
+    filter_form: &str,
+    query: &str,
+    disk_warnings: &[storage::SlowDisk],
+    kid_profile: bool,
+) -> String {
     let mut html = String::from(
         r#"<!DOCTYPE html>
 <html>
@@ -130,6 +617,15 @@ fn generate_video_list_html(videos: &[VideoEntry], server_url: &str) -> String {
             color: #007bff; 
         }
         .video-item a:hover { text-decoration: underline; }
+        .video-problem { color: #c00; font-size: 0.9em; margin-bottom: 5px; }
+        .video-extras { font-size: 0.9em; color: #666; margin-top: 5px; }
+        .audio-filter { margin-bottom: 20px; }
+        .disk-warning {
+            background-color: #fff3cd;
+            padding: 15px;
+            border-radius: 5px;
+            margin-bottom: 20px;
+        }
     </style>
 </head>
 <body>
@@ -139,26 +635,85 @@ fn generate_video_list_html(videos: &[VideoEntry], server_url: &str) -> String {
 
     // Add server info
     html.push_str(&format!(
-        "<div class=\"server-info\"><strong>Server URL:</strong> {}</div>",
+        "<div class=\"server-info\"><strong>Server URL:</strong> {}",
         server_url
     ));
+    if let Some(session) = session {
+        html.push_str(&format!(
+            " &middot; Logged in as {} ({}, <a href=\"/logout\">log out</a>)",
+            http::html_escape(&session.user),
+            session.role.as_str()
+        ));
+    }
+    // Without a session the server is open, and everyone is an admin
+    if session.is_none_or(|session| session.role == Role::Admin) {
+        html.push_str(" &middot; <a href=\"/admin/analytics\">Playback analytics</a>");
+    }
+    if session.is_some_and(|session| session.role == Role::Admin) {
+        html.push_str(" &middot; <a href=\"/admin/guests\">Guest links</a>");
+    }
+    html.push_str(" &middot; <a href=\"/connect\">Connect another device</a>");
+    if kid_profile {
+        html.push_str(&format!(
+            " &middot; <a href=\"{}\">Kid profile</a>",
+            kids::ENTER_PATH
+        ));
+    }
+    html.push_str("</div>");
+    if session.is_none_or(|session| session.role == Role::Admin) {
+        html.push_str(&storage::generate_warning_html(disk_warnings));
+    }
+    html.push_str(filter_form);
 
     if videos.is_empty() {
         html.push_str("<p>No video files found in the directory.</p>");
     } else {
         html.push_str("<ul class=\"video-list\">");
-        for video in videos {
-            if let Some(filename) = video.path.file_name() {
-                if let Some(name) = filename.to_str() {
-                    let full_url = format!("{}/{}", server_url, video.alias);
-                    html.push_str(&format!(
-                        r#"<li class="video-item">
-                            <div class="video-name">{}</div>
-                            <div class="video-url"><a href="{}" target="_blank">{}</a></div>
-                        </li>"#,
-                        name, full_url, full_url
-                    ));
-                }
+        for video in videos
+            .iter()
+            .filter(|video| video.extra_of.is_none() && video.episode.is_none())
+        {
+            if let Some(filename) = video.path.file_name()
+                && let Some(name) = filename.to_str()
+            {
+                let full_url = format!("{}/{}{}", server_url, video.alias, query);
+                let problem = match &video.problem {
+                    Some(problem) => format!(
+                        "<div class=\"video-problem\">&#9888; Possibly corrupt: {}</div>",
+                        http::html_escape(problem)
+                    ),
+                    None => String::new(),
+                };
+                let episodes: Vec<(&str, String)> = videos
+                    .iter()
+                    .filter_map(|entry| Some((entry, entry.episode.as_ref()?)))
+                    .filter(|(_, episode)| episode.of == video.alias)
+                    .map(|(entry, episode)| (entry.alias.as_str(), episode.title.clone()))
+                    .collect();
+                let extras: Vec<(&str, String)> = videos
+                    .iter()
+                    .filter(|extra| extra.extra_of.as_ref() == Some(&video.alias))
+                    .map(|extra| {
+                        let name = extra.path.file_name().unwrap().to_str().unwrap();
+                        (extra.alias.as_str(), name.to_string())
+                    })
+                    .collect();
+                html.push_str(&format!(
+                    r#"<li class="video-item">
+                        <div class="video-name">{}</div>
+                        {}
+                        <div class="video-url"><a href="{}" target="_blank">{}</a> &middot; <a href="/watch/{}{}">Watch in browser</a></div>
+                        {}{}
+                    </li>"#,
+                    name,
+                    problem,
+                    full_url,
+                    full_url,
+                    video.alias,
+                    query,
+                    related_links("Episodes", &episodes, server_url, query),
+                    related_links("Extras", &extras, server_url, query)
+                ));
             }
         }
         html.push_str("</ul>");
@@ -168,58 +723,375 @@ fn generate_video_list_html(videos: &[VideoEntry], server_url: &str) -> String {
     html
 }
 
-async fn router(
-    req: Request<Incoming>,
-    video_list: Arc<Vec<VideoEntry>>,
-    server_url: Arc<String>,
-) -> Result<Response<Full<Bytes>>, Infallible> {
-    let path = req.uri().path();
-    let method = req.method();
+/// A line of links to entries belonging to a title, given as (alias, name).
+fn related_links(label: &str, entries: &[(&str, String)], server_url: &str, query: &str) -> String {
+    if entries.is_empty() {
+        return String::new();
+    }
+    let links: Vec<String> = entries
+        .iter()
+        .map(|(alias, name)| {
+            format!(
+                "<a href=\"{}/{}{}\" target=\"_blank\">{}</a>",
+                server_url,
+                alias,
+                query,
+                http::html_escape(name)
+            )
+        })
+        .collect();
+    format!(
+        "<div class=\"video-extras\">{}: {}</div>",
+        label,
+        links.join(", ")
+    )
+}
 
-    match (method, path) {
-        (&Method::GET, "/") => list_videos_handler(video_list, server_url, req).await,
-        (&Method::GET, path) => {
-            let filename = path.strip_prefix('/').unwrap_or(path);
+/// What a route produced: a generated page, or a video file to deliver.
+enum Reply {
+    Page(Response<Full<Bytes>>),
+    Video(VideoEntry),
+    /// A video changed on the fly.
+    #[cfg(feature = "transcoding")]
+    Remux(PathBuf, Remux),
+}
 
-            // Find video by alias or by filename
-            let video_entry = video_list.iter().find(|v| {
-                v.alias == filename || v.path.file_name().unwrap().to_str().unwrap() == filename
-            });
+async fn router(state: Arc<AppState>, req: Request<Incoming>) -> middleware::Response {
+    let class = middleware::route_class(&req);
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
 
-            if let Some(entry) = video_entry {
-                serve_video(&entry.path).await
-            } else {
-                not_found()
-            }
+    let reply = match route(&state, req).await {
+        Ok(reply) => reply,
+        Err(never) => match never {},
+    };
+    let transport = &state.config.transport;
+    match reply {
+        Reply::Page(response) => transport::from_page(response, transport.strategy(class)).await,
+        Reply::Video(video) => {
+            serve_video(
+                &video,
+                range.as_deref(),
+                transport.strategy(class),
+                &state.disk,
+            )
+            .await
         }
-        _ => not_found(),
+        #[cfg(feature = "transcoding")]
+        Reply::Remux(path, remux) => serve_remux(
+            &state.config.ffmpeg,
+            &path,
+            &remux,
+            transport.strategy(class),
+        ),
     }
 }
 
-async fn serve_video(video_path: &Path) -> Result<Response<Full<Bytes>>, Infallible> {
-    match fs::read(video_path) {
-        Ok(content) => {
-            let mime_type = get_mime_type(video_path.to_str().unwrap());
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", mime_type)
-                .header("Accept-Ranges", "bytes")
-                .header("Cache-Control", "public, max-age=3600")
-                .body(Full::new(Bytes::from(content)))
-                .unwrap();
-            Ok(response)
+/// Answers a request that made it through the middleware stack, which has
+/// resolved its route and established its caller.
+async fn route(state: &AppState, mut req: Request<Incoming>) -> Result<Reply, Infallible> {
+    let server_url = req
+        .extensions()
+        .get::<Connection>()
+        .expect("the connection is added before the stack")
+        .server_url
+        .clone();
+    let Resolved(route) = *req
+        .extensions()
+        .get::<Resolved>()
+        .expect("resolved by the routing layer");
+    let caller = req
+        .extensions_mut()
+        .remove::<Caller>()
+        .expect("established by the authentication layer");
+    let auth = &state.auth;
+    let sessions = &auth.sessions;
+
+    let session = match caller {
+        Caller::Public => {
+            return match (route, &auth.oidc) {
+                (Some(Route::LoginForm), _) => {
+                    login_page(sessions, StatusCode::OK, None).map(Reply::Page)
+                }
+                (Some(Route::Login), _) => login_handler(sessions, req).await.map(Reply::Page),
+                (Some(Route::Logout), _) => logout_handler(sessions).map(Reply::Page),
+                (Some(Route::GuestLogin { token }), _) => {
+                    guest_login_handler(auth, token.get(req.uri().path())).map(Reply::Page)
+                }
+                (Some(Route::SsoLogin), Some(oidc)) => sso_login_handler(sessions, oidc, req)
+                    .await
+                    .map(Reply::Page),
+                (Some(Route::SsoCallback), Some(oidc)) => sso_callback_handler(sessions, oidc, req)
+                    .await
+                    .map(Reply::Page),
+                _ => not_found().map(Reply::Page),
+            };
+        }
+        Caller::Api(caller) => {
+            let endpoint = match route {
+                Some(Route::Api(endpoint)) => Some(endpoint),
+                _ => None,
+            };
+            let kid = state.config.kids.is_active(&req);
+            return api::handle(req, endpoint, caller, state, &server_url, kid)
+                .await
+                .map(Reply::Page);
+        }
+        Caller::User(session) => session,
+    };
+    let path = req.uri().path();
+    let kids = &state.config.kids;
+    let kid = kids.is_active(&req);
+    let visible = |video: &VideoEntry| {
+        (!kid || kids.allows(video)) && session.as_ref().is_none_or(|s| s.allows(video))
+    };
+    let is_admin = session.as_ref().is_none_or(|s| s.role == Role::Admin);
+
+    match route {
+        Some(Route::EnterKidProfile | Route::LeaveKidProfile) if kids.is_enabled() => {
+            kid_profile_handler(matches!(route, Some(Route::EnterKidProfile))).map(Reply::Page)
+        }
+        Some(Route::Connect | Route::Analytics | Route::Guests) if kid => {
+            forbidden().map(Reply::Page)
         }
+        Some(Route::Catalog) if kid => {
+            let videos: Vec<VideoEntry> = state
+                .library
+                .videos()
+                .iter()
+                .filter(|video| visible(video))
+                .cloned()
+                .collect();
+            html_page(kids::generate_catalog_html(&videos)).map(Reply::Page)
+        }
+        Some(Route::Catalog) => list_videos_handler(state, server_url, session, req)
+            .await
+            .map(Reply::Page),
+        Some(Route::Connect) => {
+            html_page(connect::generate_connect_html(&state.addresses.urls())).map(Reply::Page)
+        }
+        Some(Route::Analytics) => {
+            if auth.login_required() && !is_admin {
+                return forbidden().map(Reply::Page);
+            }
+            let html = analytics::generate_heatmap_html(&state.analytics, &state.library.videos());
+            html_page(html).map(Reply::Page)
+        }
+        // Guests log in with a session, so there are none on an open server
+        Some(Route::Guests) if auth.login_required() && is_admin => {
+            html_page(guests::generate_guests_html(&auth.guests.list())).map(Reply::Page)
+        }
+        Some(Route::Guests) => forbidden().map(Reply::Page),
+        Some(Route::Watch { alias }) => {
+            let alias = alias.get(path);
+            let video = state
+                .library
+                .videos()
+                .iter()
+                .find(|video| video.alias == alias && visible(video))
+                .cloned();
+            match video {
+                Some(video) => {
+                    let src = match req.uri().query() {
+                        Some(query) => format!("/{}?{}", video.alias, query),
+                        None => format!("/{}", video.alias),
+                    };
+                    // Anything but MP4 may not play natively, so offer it
+                    // rewrapped if its codecs fit into MP4
+                    #[cfg(feature = "transcoding")]
+                    let fallback = (get_mime_type(&video.alias) != "video/mp4"
+                        && probe::fits_mp4(&video.path))
+                    .then(|| {
+                        let separator = if src.contains('?') { '&' } else { '?' };
+                        format!("{}{}{}=mp4", src, separator, remux::FORMAT_PARAM)
+                    });
+                    #[cfg(not(feature = "transcoding"))]
+                    let fallback: Option<String> = None;
+                    // Remuxed streams seek by asking for another start
+                    #[cfg(feature = "transcoding")]
+                    let remuxed = !Remux::of(&req, &video).is_noop();
+                    #[cfg(not(feature = "transcoding"))]
+                    let remuxed = false;
+                    let html =
+                        player::generate_player_html(&video, &src, fallback.as_deref(), remuxed);
+                    html_page(html).map(Reply::Page)
+                }
+                None => not_found().map(Reply::Page),
+            }
+        }
+        Some(Route::Video { name }) => {
+            let Some(video) = state
+                .library
+                .find(name.get(path))
+                .filter(|video| visible(video))
+            else {
+                return not_found().map(Reply::Page);
+            };
+
+            #[cfg(feature = "transcoding")]
+            {
+                let remux = Remux::of(&req, &video);
+                if !remux.is_noop() {
+                    return Ok(Reply::Remux(video.path, remux));
+                }
+            }
+            Ok(Reply::Video(video))
+        }
+        _ => not_found().map(Reply::Page),
+    }
+}
+
+async fn serve_video(
+    video: &VideoEntry,
+    range: Option<&str>,
+    strategy: &Strategy,
+    disk: &Arc<DiskMonitor>,
+) -> Response<transport::Body> {
+    let video_path = &video.path;
+    let file = match fs::File::open(video_path).and_then(|f| f.metadata().map(|m| (f, m.len()))) {
+        Ok(file) => file,
         Err(_) => {
-            let response = Response::builder()
+            return Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .header("Content-Type", "text/html")
-                .body(Full::new(Bytes::from("<h1>404 Video Not Found</h1>")))
+                .body(transport::Body::from_bytes(
+                    Bytes::from("<h1>404 Video Not Found</h1>"),
+                    strategy,
+                ))
                 .unwrap();
-            Ok(response)
+        }
+    };
+    let (file, len) = file;
+
+    let (status, start, count) = match range.and_then(|range| transport::parse_range(range, len)) {
+        Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        Some(Err(())) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", len))
+                .body(transport::Body::from_bytes(Bytes::new(), strategy))
+                .unwrap();
+        }
+        None => (StatusCode::OK, 0, len),
+    };
+
+    let body = match open_video_body(file, start, count, strategy, || {
+        let bitrate = video
+            .duration
+            .filter(|duration| !duration.is_zero())
+            .map(|duration| (len as f64 / duration.as_secs_f64()) as u64);
+        disk.watch(video_path, bitrate)
+    })
+    .await
+    {
+        Ok(body) => body,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", video_path.display(), err);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/html")
+                .body(transport::Body::from_bytes(
+                    Bytes::from("<h1>500 Internal Server Error</h1>"),
+                    strategy,
+                ))
+                .unwrap();
+        }
+    };
+
+    let mime_type = get_mime_type(video_path.to_str().unwrap());
+    let mut response = Response::builder()
+        .status(status)
+        .header("Content-Type", mime_type)
+        .header("Accept-Ranges", "bytes")
+        .header("Cache-Control", "public, max-age=3600");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, start + count - 1, len),
+        );
+    }
+    response.body(body).unwrap()
+}
+
+/// Streams a video remuxed through ffmpeg. The output has no known length,
+/// so range requests are not supported.
+#[cfg(feature = "transcoding")]
+fn serve_remux(
+    ffmpeg: &str,
+    video_path: &Path,
+    remux: &Remux,
+    strategy: &Strategy,
+) -> Response<transport::Body> {
+    match remux::spawn(ffmpeg, video_path, remux) {
+        Ok(child) => Response::builder()
+            .header("Content-Type", remux.mime_type())
+            .header("Accept-Ranges", "none")
+            .body(transport::Body::from_process(child, strategy))
+            .unwrap(),
+        Err(err) => {
+            eprintln!("Failed to run {}: {}", ffmpeg, err);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/html")
+                .body(transport::Body::from_bytes(
+                    Bytes::from("<h1>500 Internal Server Error</h1>"),
+                    strategy,
+                ))
+                .unwrap()
         }
     }
 }
 
+/// Prepares `count` bytes starting at `start` for delivery as configured.
+/// `watch` is only called for streamed delivery, whose reads it times.
+async fn open_video_body(
+    mut file: fs::File,
+    start: u64,
+    count: u64,
+    strategy: &Strategy,
+    watch: impl FnOnce() -> storage::ReadWatch,
+) -> io::Result<transport::Body> {
+    match strategy.delivery {
+        Delivery::Buffered => {
+            let mut content = vec![0; count as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut content)?;
+            Ok(transport::Body::from_bytes(Bytes::from(content), strategy))
+        }
+        #[cfg(unix)]
+        Delivery::Mmap => {
+            let mapping = transport::map_file(&file, (start + count) as usize)?;
+            let content = mapping.slice(start as usize..(start + count) as usize);
+            Ok(transport::Body::from_bytes(content, strategy))
+        }
+        _ => {
+            let mut file = tokio::fs::File::from_std(file);
+            file.seek(SeekFrom::Start(start)).await?;
+            Ok(transport::Body::from_file(file, count, strategy, watch()))
+        }
+    }
+}
+
+fn html_page(html: String) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Full::new(Bytes::from(html)))
+        .unwrap();
+    Ok(response)
+}
+
+fn forbidden() -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header("Content-Type", "text/html")
+        .body(Full::new(Bytes::from("<h1>403 Forbidden</h1>")))
+        .unwrap();
+    Ok(response)
+}
+
 fn not_found() -> Result<Response<Full<Bytes>>, Infallible> {
     let response = Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -248,38 +1120,343 @@ fn get_mime_type(filename: &str) -> &'static str {
     }
 }
 
+/// Serves HTTP on `stream` until the client is done with it, or has moved no
+/// data for `idle_timeout`.
+async fn serve_connection<I, S>(
+    stream: I,
+    service: S,
+    peer: SocketAddr,
+    idle_timeout: Option<Duration>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: hyper::service::Service<
+            Request<Incoming>,
+            Response = Response<transport::Body>,
+            Error = Infallible,
+        >,
+{
+    let (stream, idle) = idle::watch(stream);
+    let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+    tokio::select! {
+        result = connection => {
+            if let Err(err) = result {
+                eprintln!("Error serving connection: {:?}", err);
+            }
+        }
+        () = idle.expired(idle_timeout) => {
+            eprintln!("Closed idle connection from {}", peer);
+        }
+    }
+}
+
+/// The middleware stack around the router.
+fn build_service(
+    state: &Arc<AppState>,
+    request_timeout: Duration,
+    access_log: bool,
+) -> impl Service<
+    Request<Incoming>,
+    Response = middleware::Response,
+    Error = Infallible,
+    Future: Send + 'static,
+> + Clone
++ Send
++ 'static {
+    // Innermost first: each layer wraps the ones above it
+    let service = ServiceFn({
+        let state = state.clone();
+        move |req| router(state.clone(), req)
+    });
+    let service = MiddlewareLayer(Authentication {
+        state: state.clone(),
+    })
+    .layer(service);
+    let service = MiddlewareLayer(MaintenanceGate {
+        state: state.clone(),
+    })
+    .layer(service);
+    // Compress before throttling, so the limit applies to what is sent
+    let service = MiddlewareLayer(Compression {
+        state: state.clone(),
+    })
+    .layer(service);
+    let service = MiddlewareLayer(RateLimit {
+        state: state.clone(),
+    })
+    .layer(service);
+    let service = MiddlewareLayer(Routing).layer(service);
+    let service = MiddlewareLayer(Timeout {
+        limit: request_timeout,
+    })
+    .layer(service);
+    MiddlewareLayer(AccessLog {
+        enabled: access_log,
+    })
+    .layer(service)
+}
+
+/// Accepts connections on `listener` and answers them with `service`, until
+/// accepting fails.
+async fn serve<S>(
+    listener: TcpListener,
+    service: S,
+    state: Arc<AppState>,
+    #[cfg(feature = "tls")] tls: Option<Arc<tls::TlsAcceptor>>,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = middleware::Response, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let bind = listener.local_addr()?.ip();
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        let service_clone = service.clone();
+        let state_clone = state.clone();
+        #[cfg(feature = "tls")]
+        let tls_clone = tls.clone();
+
+        tokio::task::spawn(async move {
+            // Links in the catalog and API point at the address this client
+            // can reach
+            let local = stream.local_addr().map_or(bind, |local| local.ip());
+            let connection = Connection {
+                peer: peer.ip().to_canonical(),
+                server_url: Arc::new(
+                    state_clone
+                        .addresses
+                        .url_for(peer.ip().to_canonical(), local.to_canonical()),
+                ),
+            };
+
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(connection.clone());
+                service_clone.clone().call(req)
+            });
+
+            #[cfg(feature = "tls")]
+            if let Some(acceptor) = tls_clone {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        eprintln!("TLS handshake with {} failed: {}", peer, err);
+                        return;
+                    }
+                };
+                serve_connection(stream, service, peer, idle_timeout).await;
+                return;
+            }
+
+            serve_connection(stream, service, peer, idle_timeout).await;
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
 
-    let addr: SocketAddr = format!("{}:{}", args.host, args.port).parse()?;
-    let local_ip = get_local_ip().unwrap_or_else(|_| "localhost".to_string());
-    let server_url = Arc::new(format!("http://{}:{}", local_ip, args.port));
+    if let Some(Command::Check {
+        #[cfg(feature = "transcoding")]
+        full,
+    }) = &args.command
+    {
+        #[cfg(feature = "transcoding")]
+        let ffmpeg = full.then_some(args.ffmpeg.as_str());
+        #[cfg(not(feature = "transcoding"))]
+        let ffmpeg = None;
+        let problems = check::run(&args.video_dir, ffmpeg);
+        std::process::exit(if problems == 0 { 0 } else { 1 });
+    }
 
-    println!("Starting video server on {}", addr);
-    println!("Video directory: {}", args.video_dir);
-    println!("Server URL: {}", server_url);
+    let doctor = match &args.command {
+        Some(Command::Doctor { network }) if !network => {
+            eprintln!("Nothing to diagnose; pass --network to measure serving.");
+            std::process::exit(2);
+        }
+        Some(Command::Doctor { .. }) => true,
+        _ => false,
+    };
 
-    let video_list = Arc::new(get_video_list(&args.video_dir));
-    println!("Found {} video files.", video_list.len());
+    let addr = SocketAddr::new(args.host.parse()?, args.port);
 
-    let listener = TcpListener::bind(addr).await?;
+    #[cfg(feature = "tls")]
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(tls::TlsAcceptor::new(
+            cert,
+            key,
+            args.tls_client_ca.as_deref(),
+        )?)),
+        _ => None,
+    };
+    #[cfg(feature = "tls")]
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    #[cfg(not(feature = "tls"))]
+    let scheme = "http";
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+    let addresses = Addresses {
+        scheme,
+        bind: addr.ip(),
+        port: args.port,
+        public_url: args.public_url,
+    };
+    if !doctor {
+        println!("Starting video server on {}", addr);
+        println!("Video directory: {}", args.video_dir);
+        #[cfg(feature = "tls")]
+        if args.tls_client_ca.is_some() {
+            println!("Requiring client certificates.");
+        }
+        println!("Reachable at (QR codes on /connect):");
+        for url in addresses.urls() {
+            println!("  {:<28} {}", url.label, url.url);
+        }
+    }
 
-        let video_list_clone = video_list.clone();
-        let server_url_clone = server_url.clone();
+    let library = Library::scan(args.video_dir);
+    println!("Found {} video files.", library.videos().len());
 
-        tokio::task::spawn(async move {
-            let service = service_fn(move |req| {
-                router(req, video_list_clone.clone(), server_url_clone.clone())
-            });
+    let oidc = match args.oidc_issuer {
+        Some(issuer) => Some(Oidc::new(OidcConfig {
+            issuer,
+            client_id: args.oidc_client_id.unwrap_or_default(),
+            client_secret: args.oidc_client_secret.unwrap_or_default(),
+            redirect_url: args.oidc_redirect_url,
+            role_claim: args.oidc_role_claim,
+            admin_values: args.oidc_admin_group,
+            viewer_values: args.oidc_viewer_group,
+            scheme,
+            #[cfg(feature = "tls")]
+            ca: args.oidc_ca,
+        })?),
+        None => None,
+    };
 
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                eprintln!("Error serving connection: {:?}", err);
+    let sessions = Sessions::new(
+        args.password,
+        oidc.is_some(),
+        args.session_secret,
+        args.previous_session_secret,
+        Duration::from_secs(args.session_ttl * 3600),
+        scheme == "https",
+    );
+    if sessions.is_enabled() {
+        println!("Login required.");
+    }
+
+    let forward_auth = if args.trusted_proxy.is_empty() {
+        None
+    } else {
+        println!(
+            "Trusting forward-auth headers from {}.",
+            args.trusted_proxy
+                .iter()
+                .map(IpNet::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Some(ForwardAuth {
+            trusted_proxies: args.trusted_proxy,
+            user_headers: args.forward_auth_user_header,
+            groups_header: args.forward_auth_groups_header,
+            admin_groups: args.forward_auth_admin_group,
+        })
+    };
+
+    let auth = Auth {
+        sessions,
+        oidc,
+        forward_auth,
+        api_keys: ApiKeys::load(args.api_keys_file)?,
+        guests: Guests::default(),
+    };
+
+    let state = Arc::new(AppState {
+        library,
+        config: Config {
+            #[cfg(feature = "transcoding")]
+            ffmpeg: args.ffmpeg,
+            transport: match &args.transport_config {
+                Some(path) => TransportConfig::load(path)?,
+                None => TransportConfig::default(),
+            },
+            kids: KidProfile::new(args.kid_video),
+        },
+        auth,
+        analytics: Arc::new(Analytics::load(args.analytics_file)?),
+        maintenance: Maintenance::default(),
+        addresses,
+        disk: Arc::new(DiskMonitor::default()),
+    });
+
+    let service = build_service(
+        &state,
+        Duration::from_secs(args.request_timeout),
+        args.access_log,
+    );
+    let idle_timeout = (args.idle_timeout > 0).then(|| Duration::from_secs(args.idle_timeout));
+
+    if doctor {
+        // Plain HTTP on a port of its own, next to any running server
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let local = listener.local_addr()?;
+        tokio::spawn(serve(
+            listener,
+            service,
+            state.clone(),
+            #[cfg(feature = "tls")]
+            None,
+            idle_timeout,
+        ));
+        if let Err(err) = doctor::run(&state, local).await {
+            eprintln!("Doctor failed: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    tokio::spawn(analytics::flush_periodically(state.analytics.clone()));
+
+    let listener = TcpListener::bind(addr).await?;
+    tokio::select! {
+        result = serve(
+            listener,
+            service,
+            state.clone(),
+            #[cfg(feature = "tls")]
+            tls,
+            idle_timeout,
+        ) => result?,
+        () = shutdown_signal() => println!("Shutting down."),
+    }
+    // Keep what was watched since the last periodic write
+    if let Err(err) = state.analytics.flush() {
+        eprintln!("Failed to save analytics: {}", err);
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
             }
-        });
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
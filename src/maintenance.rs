@@ -0,0 +1,119 @@
+//! Maintenance mode: everyone but admins gets a "back soon" page while the
+//! library is being reorganized.
+
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use crate::http;
+
+/// Retry-After sent when no end of the maintenance was announced.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone)]
+pub struct Status {
+    pub message: String,
+    /// When the maintenance is expected to be over, if announced.
+    pub until: Option<SystemTime>,
+}
+
+impl Status {
+    /// Seconds clients should wait before trying again.
+    pub fn retry_after(&self) -> u64 {
+        self.until
+            .and_then(|until| until.duration_since(SystemTime::now()).ok())
+            .filter(|remaining| !remaining.is_zero())
+            .unwrap_or(DEFAULT_RETRY_AFTER)
+            .as_secs()
+            .max(1)
+    }
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    status: RwLock<Option<Status>>,
+}
+
+impl Maintenance {
+    /// The current maintenance, if the server is in maintenance mode.
+    pub fn status(&self) -> Option<Status> {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Starts maintenance mode, expected to last `duration` if given.
+    /// Returns `None`, leaving the mode as it was, if the end is too far off
+    /// to represent.
+    pub fn start(&self, message: String, duration: Option<Duration>) -> Option<Status> {
+        let until = match duration {
+            Some(duration) => Some(SystemTime::now().checked_add(duration)?),
+            None => None,
+        };
+        let status = Status { message, until };
+        *self.status.write().unwrap() = Some(status.clone());
+        Some(status)
+    }
+
+    /// Ends maintenance mode, returning whether it was on.
+    pub fn end(&self) -> bool {
+        self.status.write().unwrap().take().is_some()
+    }
+}
+
+pub fn generate_maintenance_html(status: &Status) -> String {
+    let minutes = status.retry_after().div_ceil(60);
+    let message = if status.message.is_empty() {
+        "The library is being reorganized.".to_string()
+    } else {
+        http::html_escape(&status.message)
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit - Back Soon</title>
+    <meta http-equiv="refresh" content="{refresh}">
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 40px; }}
+        h1 {{ color: #333; }}
+        .maintenance {{
+            max-width: 480px;
+            margin: 80px auto;
+            padding: 30px;
+            background-color: #e7f3ff;
+            border-radius: 5px;
+            text-align: center;
+        }}
+        .maintenance p {{ color: #666; }}
+    </style>
+</head>
+<body>
+    <div class="maintenance">
+        <h1>Back soon</h1>
+        <p>{message}</p>
+        <p>Expected back in about {minutes} minute{plural}. This page reloads by itself.</p>
+    </div>
+</body>
+</html>"#,
+        refresh = status.retry_after(),
+        plural = if minutes == 1 { "" } else { "s" },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_an_end_too_far_off() {
+        let maintenance = Maintenance::default();
+        assert!(
+            maintenance
+                .start(String::new(), Some(Duration::MAX))
+                .is_none()
+        );
+        assert!(maintenance.status().is_none());
+
+        let status = maintenance.start(String::new(), Some(Duration::from_secs(600)));
+        assert!(status.is_some_and(|status| status.retry_after() > 590));
+    }
+}
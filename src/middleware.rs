@@ -0,0 +1,391 @@
+//! Request handling as a stack of tower layers around the router. Each
+//! cross-cutting concern (logging, timeouts, compression, rate limiting,
+//! maintenance, authentication) is one [`Middleware`], and passes what it
+//! found out down the stack in the request's extensions.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{LOCATION, RETRY_AFTER};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::auth::{ApiCaller, Auth};
+use crate::json;
+use crate::maintenance;
+use crate::routes::{self, Route};
+use crate::session::Session;
+use crate::state::AppState;
+use crate::transport::{self, RouteClass};
+
+pub type Request = hyper::Request<Incoming>;
+pub type Response = hyper::Response<transport::Body>;
+type BoxFuture = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+/// The connection a request came in on.
+#[derive(Clone)]
+pub struct Connection {
+    pub peer: IpAddr,
+    /// Base URL for links, as reachable by this client.
+    pub server_url: Arc<String>,
+}
+
+/// The route a request resolved to, if any.
+#[derive(Clone, Copy)]
+pub struct Resolved(pub Option<Route>);
+
+/// Who sent the request, as far as its route needs to know.
+#[derive(Clone)]
+pub enum Caller {
+    /// Login and SSO routes, which are open to everyone.
+    Public,
+    /// An `/api` request, or `None` if it is unauthorized.
+    Api(Option<ApiCaller>),
+    /// A page or video request; `None` on an open server.
+    User(Option<Session>),
+}
+
+/// One cross-cutting concern: sees every request and decides what to pass
+/// on to `next`, the rest of the stack.
+pub trait Middleware: Clone + Send + Sync + 'static {
+    fn handle<S>(&self, req: Request, next: S) -> impl Future<Output = Response> + Send
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send;
+}
+
+/// Wraps a [`Middleware`] as a tower [`Layer`].
+#[derive(Clone)]
+pub struct MiddlewareLayer<M>(pub M);
+
+impl<M: Clone, S> Layer<S> for MiddlewareLayer<M> {
+    type Service = Stack<M, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Stack {
+            middleware: self.0.clone(),
+            inner,
+        }
+    }
+}
+
+/// A [`Middleware`] in front of the service `S`. Every service in the stack
+/// is always ready, so readiness is not propagated.
+#[derive(Clone)]
+pub struct Stack<M, S> {
+    middleware: M,
+    inner: S,
+}
+
+impl<M, S> Service<Request> for Stack<M, S>
+where
+    M: Middleware,
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> BoxFuture {
+        let middleware = self.middleware.clone();
+        let next = self.inner.clone();
+        Box::pin(async move { Ok(middleware.handle(req, next).await) })
+    }
+}
+
+/// The innermost service, handing requests to an async function.
+#[derive(Clone)]
+pub struct ServiceFn<F>(pub F);
+
+impl<F, Fut> Service<Request> for ServiceFn<F>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let response = (self.0)(req);
+        Box::pin(async move { Ok(response.await) })
+    }
+}
+
+async fn call<S>(mut next: S, req: Request) -> Response
+where
+    S: Service<Request, Response = Response, Error = Infallible>,
+{
+    match next.call(req).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+fn connection(req: &Request) -> &Connection {
+    req.extensions()
+        .get::<Connection>()
+        .expect("the connection is added before the stack")
+}
+
+/// The route class of a request, once [`Routing`] has resolved it.
+pub fn route_class(req: &Request) -> RouteClass {
+    let Resolved(route) = req
+        .extensions()
+        .get::<Resolved>()
+        .expect("routes are resolved before delivery");
+    RouteClass::of(route.as_ref())
+}
+
+fn page(status: StatusCode, html: &'static str) -> Response {
+    hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html")
+        .body(transport::Body::whole(Bytes::from_static(html.as_bytes())))
+        .unwrap()
+}
+
+/// Logs one line per request, once its response has started.
+#[derive(Clone)]
+pub struct AccessLog {
+    pub enabled: bool,
+}
+
+impl Middleware for AccessLog {
+    async fn handle<S>(&self, req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        if !self.enabled {
+            return call(next, req).await;
+        }
+        let started = Instant::now();
+        let peer = connection(&req).peer;
+        let method = req.method().clone();
+        // Only the path: queries can carry login codes and filters
+        let path = req.uri().path().to_string();
+        let response = call(next, req).await;
+        println!(
+            "{} {} {} {} {}ms",
+            peer,
+            method,
+            path,
+            response.status().as_u16(),
+            started.elapsed().as_millis()
+        );
+        response
+    }
+}
+
+/// Turns requests whose response doesn't start within `limit` away. Only
+/// producing the response is timed, not streaming its body.
+#[derive(Clone)]
+pub struct Timeout {
+    pub limit: Duration,
+}
+
+impl Middleware for Timeout {
+    async fn handle<S>(&self, req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let path = req.uri().path().to_string();
+        match tokio::time::timeout(self.limit, call(next, req)).await {
+            Ok(response) => response,
+            Err(_) => {
+                eprintln!("Request for {} timed out", path);
+                page(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "<h1>503 Service Unavailable</h1><p>The request took too long.</p>",
+                )
+            }
+        }
+    }
+}
+
+/// Gzips generated responses as the route class's strategy says.
+#[derive(Clone)]
+pub struct Compression {
+    pub state: Arc<AppState>,
+}
+
+impl Middleware for Compression {
+    async fn handle<S>(&self, req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let class = route_class(&req);
+        let accepts_gzip = transport::accepts_gzip(req.headers());
+        let response = call(next, req).await;
+        self.state
+            .config
+            .transport
+            .compress(class, accepts_gzip, response)
+    }
+}
+
+/// Caps the bandwidth of each response as the route class's strategy says.
+#[derive(Clone)]
+pub struct RateLimit {
+    pub state: Arc<AppState>,
+}
+
+impl Middleware for RateLimit {
+    async fn handle<S>(&self, req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let class = route_class(&req);
+        let response = call(next, req).await;
+        match self.state.config.transport.strategy(class).rate_limit {
+            Some(rate) => response.map(|body| body.with_rate_limit(rate)),
+            None => response,
+        }
+    }
+}
+
+/// Resolves the route, for the layers below and the router. Delivery layers
+/// pick their strategy from it, so it goes outside them.
+#[derive(Clone)]
+pub struct Routing;
+
+impl Middleware for Routing {
+    async fn handle<S>(&self, mut req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let route = routes::resolve(req.method(), req.uri().path());
+        req.extensions_mut().insert(Resolved(route));
+        call(next, req).await
+    }
+}
+
+/// Whether the request is for a login route this server offers.
+fn is_login_route(auth: &Auth, req: &Request) -> bool {
+    let route = req.extensions().get::<Resolved>();
+    // Guest links work with any login method; behind a forward-auth proxy
+    // only if the proxy lets them through (see the README)
+    if let Some(Resolved(Some(Route::GuestLogin { .. }))) = route {
+        return auth.login_required();
+    }
+    if !auth.sessions.is_enabled() {
+        return false;
+    }
+    match route {
+        Some(Resolved(Some(Route::LoginForm | Route::Logout))) => true,
+        Some(Resolved(Some(Route::Login))) => auth.sessions.has_password(),
+        Some(Resolved(Some(Route::SsoLogin | Route::SsoCallback))) => auth.oidc.is_some(),
+        _ => false,
+    }
+}
+
+/// Answers everyone but admins with a "back soon" page (JSON for the API)
+/// during maintenance. Logging in stays possible, so admins can get in.
+#[derive(Clone)]
+pub struct MaintenanceGate {
+    pub state: Arc<AppState>,
+}
+
+impl Middleware for MaintenanceGate {
+    async fn handle<S>(&self, req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let Some(status) = self.state.maintenance.status() else {
+            return call(next, req).await;
+        };
+        if is_login_route(&self.state.auth, &req)
+            || self.state.auth.is_maintainer(connection(&req).peer, &req)
+        {
+            return call(next, req).await;
+        }
+
+        let (content_type, body) = if req.uri().path().starts_with("/api/") {
+            (
+                "application/json",
+                format!(
+                    "{{\"error\":{}}}",
+                    json::quote("the server is under maintenance")
+                ),
+            )
+        } else {
+            (
+                "text/html; charset=utf-8",
+                maintenance::generate_maintenance_html(&status),
+            )
+        };
+        hyper::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Content-Type", content_type)
+            .header(RETRY_AFTER, status.retry_after())
+            .body(transport::Body::whole(Bytes::from(body)))
+            .unwrap()
+    }
+}
+
+/// Establishes the [`Caller`] and turns anonymous requests away when a
+/// login is required. The API answers unauthorized requests itself, in
+/// JSON.
+#[derive(Clone)]
+pub struct Authentication {
+    pub state: Arc<AppState>,
+}
+
+impl Middleware for Authentication {
+    async fn handle<S>(&self, mut req: Request, next: S) -> Response
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Send + 'static,
+        S::Future: Send,
+    {
+        let peer = connection(&req).peer;
+        let caller = if is_login_route(&self.state.auth, &req) {
+            Caller::Public
+        } else if req.uri().path().starts_with("/api/") {
+            Caller::Api(self.state.auth.api_caller(peer, &req))
+        } else {
+            let session = self.state.auth.authenticate(peer, &req);
+            if session.is_none() && self.state.auth.login_required() {
+                return unauthorized(req.uri().path(), self.state.auth.sessions.is_enabled());
+            }
+            Caller::User(session)
+        };
+        req.extensions_mut().insert(caller);
+        call(next, req).await
+    }
+}
+
+/// Sends browsers on the catalog page to the login form, if there is one;
+/// everything else (direct video links opened in a player) gets a plain 401.
+fn unauthorized(path: &str, has_login: bool) -> Response {
+    if path == "/" && has_login {
+        hyper::Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, "/login")
+            .body(transport::Body::whole(Bytes::new()))
+            .unwrap()
+    } else {
+        page(StatusCode::UNAUTHORIZED, "<h1>401 Unauthorized</h1>")
+    }
+}
@@ -0,0 +1,310 @@
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::OnceCell;
+
+use crate::crypto;
+use crate::http::percent_encode;
+use crate::json::{self, Value};
+use crate::session::Role;
+
+pub const STATE_COOKIE_NAME: &str = "streamshit_oidc_state";
+
+/// Settings for logging in through an OpenID Connect provider.
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: Option<String>,
+    pub role_claim: String,
+    pub admin_values: Vec<String>,
+    pub viewer_values: Vec<String>,
+    /// How browsers reach us, `http` or `https`, for the default redirect URL.
+    pub scheme: &'static str,
+    /// CA to verify an HTTPS provider with instead of the system's.
+    #[cfg(feature = "tls")]
+    pub ca: Option<PathBuf>,
+}
+
+/// The user an identity provider vouched for, already mapped to a role.
+pub struct Identity {
+    pub user: String,
+    pub role: Role,
+}
+
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+/// OpenID Connect authorization code flow.
+///
+/// The provider's discovery, token and userinfo endpoints are reached over
+/// `http://` or, with the `tls` feature, `https://`; HTTPS certificates are
+/// verified against the system's CAs or a configured one. Because the ID
+/// token is never trusted on its own, identity is taken from the userinfo
+/// endpoint using the access token obtained directly from the provider.
+pub struct Oidc {
+    config: OidcConfig,
+    provider: OnceCell<Provider>,
+    #[cfg(feature = "tls")]
+    tls: crate::tls::TlsConnector,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Result<Self, String> {
+        let https = config.issuer.starts_with("https://");
+        if !https && !config.issuer.starts_with("http://") {
+            return Err(format!(
+                "OIDC issuer {} must be an http:// or https:// URL",
+                config.issuer
+            ));
+        }
+        #[cfg(not(feature = "tls"))]
+        if https {
+            return Err(format!(
+                "OIDC issuer {} needs TLS, which this build was compiled without",
+                config.issuer
+            ));
+        }
+
+        Ok(Oidc {
+            #[cfg(feature = "tls")]
+            tls: crate::tls::TlsConnector::new(config.ca.as_deref())
+                .map_err(|e| format!("OIDC: {}", e))?,
+            config,
+            provider: OnceCell::new(),
+        })
+    }
+
+    /// The callback URL registered with the provider. Unless configured, it
+    /// is derived from the Host the browser used to reach us and whether we
+    /// serve TLS.
+    pub fn redirect_url(&self, host: &str) -> String {
+        match &self.config.redirect_url {
+            Some(url) => url.clone(),
+            None => format!("{}://{}/auth/callback", self.config.scheme, host),
+        }
+    }
+
+    /// Returns a fresh random `state` value and the URL to send the browser to.
+    pub async fn authorization_url(&self, redirect_url: &str) -> Result<(String, String), String> {
+        let provider = self.provider().await?;
+        let state = URL_SAFE_NO_PAD.encode(crypto::random_bytes(24));
+        let separator = if provider.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let url = format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}",
+            provider.authorization_endpoint,
+            separator,
+            percent_encode(&self.config.client_id),
+            percent_encode(redirect_url),
+            percent_encode("openid profile email"),
+            state
+        );
+        Ok((state, url))
+    }
+
+    /// Redeems an authorization code and resolves the user behind it.
+    pub async fn exchange(&self, code: &str, redirect_url: &str) -> Result<Identity, String> {
+        let provider = self.provider().await?;
+
+        let form = format!(
+            "grant_type=authorization_code&code={}&redirect_uri={}",
+            percent_encode(code),
+            percent_encode(redirect_url)
+        );
+        let credentials = STANDARD.encode(format!(
+            "{}:{}",
+            percent_encode(&self.config.client_id),
+            percent_encode(&self.config.client_secret)
+        ));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&provider.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json")
+            .header(AUTHORIZATION, format!("Basic {}", credentials))
+            .body(Full::new(Bytes::from(form)))
+            .map_err(|e| e.to_string())?;
+        let token = self.send(request).await?;
+        let access_token = token
+            .get("access_token")
+            .and_then(Value::as_str)
+            .ok_or("token response has no access_token")?;
+
+        let request = Request::builder()
+            .uri(&provider.userinfo_endpoint)
+            .header(ACCEPT, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .body(Full::new(Bytes::new()))
+            .map_err(|e| e.to_string())?;
+        let claims = self.send(request).await?;
+
+        let user = ["preferred_username", "email", "sub"]
+            .iter()
+            .find_map(|claim| claims.get(claim).and_then(Value::as_str))
+            .ok_or("userinfo response has no subject")?
+            .to_string();
+        let role = self
+            .map_role(&claims)
+            .ok_or_else(|| format!("{} is not allowed to use this server", user))?;
+
+        Ok(Identity { user, role })
+    }
+
+    /// Maps the configured role claim (a string or a list of strings, such as
+    /// `groups`) to a role. Without any viewer values configured, everyone the
+    /// provider authenticates may watch.
+    fn map_role(&self, claims: &Value) -> Option<Role> {
+        let values: Vec<&str> = match claims.get(&self.config.role_claim) {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let matches = |wanted: &[String]| values.iter().any(|v| wanted.iter().any(|w| w == v));
+
+        if matches(&self.config.admin_values) {
+            Some(Role::Admin)
+        } else if self.config.viewer_values.is_empty() || matches(&self.config.viewer_values) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+
+    async fn provider(&self) -> Result<&Provider, String> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let request = Request::builder()
+                    .uri(url)
+                    .header(ACCEPT, "application/json")
+                    .body(Full::new(Bytes::new()))
+                    .map_err(|e| e.to_string())?;
+                let discovery = self.send(request).await?;
+                let endpoint = |name: &str| {
+                    discovery
+                        .get(name)
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                        .ok_or_else(|| format!("discovery document has no {}", name))
+                };
+
+                Ok(Provider {
+                    authorization_endpoint: endpoint("authorization_endpoint")?,
+                    token_endpoint: endpoint("token_endpoint")?,
+                    userinfo_endpoint: endpoint("userinfo_endpoint")?,
+                })
+            })
+            .await
+    }
+
+    async fn send(&self, request: Request<Full<Bytes>>) -> Result<Value, String> {
+        let uri = request.uri().to_string();
+        let response = self
+            .request(request)
+            .await
+            .map_err(|e| format!("{}: {}", uri, e))?;
+        let status = response.status();
+        let body = Limited::new(response.into_body(), 1024 * 1024)
+            .collect()
+            .await
+            .map_err(|e| format!("{}: {}", uri, e))?
+            .to_bytes();
+
+        if status != StatusCode::OK {
+            return Err(format!(
+                "{} returned {}: {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        json::parse(&String::from_utf8_lossy(&body)).map_err(|e| format!("{}: {}", uri, e))
+    }
+
+    /// Sends `request` over a connection of its own; logins are too rare
+    /// to keep connections around.
+    async fn request(
+        &self,
+        mut request: Request<Full<Bytes>>,
+    ) -> Result<Response<Incoming>, String> {
+        let uri: Uri = request.uri().clone();
+        let https = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => return Err("not an http:// or https:// URL".to_string()),
+        };
+        let authority = uri.authority().ok_or("URL has no host")?.clone();
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|e| e.to_string())?;
+        // Connections take the path alone, the host goes in its header
+        request.headers_mut().insert(
+            HOST,
+            authority.as_str().parse().map_err(|_| "invalid host")?,
+        );
+        *request.uri_mut() = uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/")
+            .parse()
+            .map_err(|_| "invalid path")?;
+
+        if https {
+            #[cfg(feature = "tls")]
+            {
+                let stream = self
+                    .tls
+                    .connect(stream, host)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                return send_over(stream, request).await;
+            }
+            #[cfg(not(feature = "tls"))]
+            return Err("this build has no TLS support".to_string());
+        }
+        send_over(stream, request).await
+    }
+}
+
+async fn send_over<S>(
+    stream: S,
+    request: Request<Full<Bytes>>,
+) -> Result<Response<Incoming>, String>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+    sender
+        .send_request(request)
+        .await
+        .map_err(|e| e.to_string())
+}
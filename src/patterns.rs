@@ -0,0 +1,57 @@
+//! File name patterns picking videos out of the flat library, for the kid
+//! profile and guest accounts.
+
+use crate::VideoEntry;
+
+/// Patterns where `*` matches any run of characters, compared
+/// case-insensitively against a video's file name.
+#[derive(Clone)]
+pub struct VideoPatterns {
+    patterns: Vec<String>,
+}
+
+impl VideoPatterns {
+    pub fn new(patterns: Vec<String>) -> Self {
+        VideoPatterns { patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Whether any pattern matches `video`. Episodes and extras are judged
+    /// by their own file, so a pattern for a show admits all its episodes.
+    pub fn matches(&self, video: &VideoEntry) -> bool {
+        let Some(name) = video.path.file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+        let name = name.to_lowercase();
+        self.patterns
+            .iter()
+            .any(|pattern| matches_pattern(&pattern.to_lowercase(), &name))
+    }
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*`: the whole name must match
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
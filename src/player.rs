@@ -0,0 +1,173 @@
+//! The in-browser player, which reports what gets watched for the playback
+//! analytics, switches to a rewrapped stream when the browser can't play
+//! the file, seeks in remuxed streams and has a sleep timer.
+
+#[cfg(feature = "transcoding")]
+use crate::remux::SEEK_PARAM;
+use crate::{VideoEntry, http, json};
+
+/// Without transcoding nothing is remuxed, so nothing seeks by time.
+#[cfg(not(feature = "transcoding"))]
+const SEEK_PARAM: &str = "";
+
+/// Where the player posts its beacons.
+pub const BEACON_PATH: &str = "/api/beacons";
+
+/// Renders the player for `video`, streaming from `src`, or from `fallback`
+/// if the browser fails to play that. `remuxed` streams, which can't be
+/// seeked by byte range, get a control jumping to a time instead.
+pub fn generate_player_html(
+    video: &VideoEntry,
+    src: &str,
+    fallback: Option<&str>,
+    remuxed: bool,
+) -> String {
+    let name = match &video.episode {
+        Some(episode) => episode.title.clone(),
+        None => video
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string(),
+    };
+
+    let fallback_attr = match fallback {
+        Some(fallback) => format!(r#" data-fallback="{}""#, http::html_escape(fallback)),
+        None => String::new(),
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{name} - Streamshit</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; margin: 40px; }}
+        video {{ width: 100%; max-height: 80vh; background-color: #000; }}
+        .sleep, .jump {{ color: #666; }}
+    </style>
+</head>
+<body>
+    <p><a href="/">Back to the catalog</a></p>
+    <h1>{name}</h1>
+    <video src="{src}"{fallback} controls autoplay></video>
+    <p class="jump" id="jump"{jump_hidden}>Jump to
+        <input id="jump-to" size="8" placeholder="h:mm:ss">
+        <button id="jump-go">Go</button>
+        <span id="position"></span>
+    </p>
+    <p class="sleep">Sleep timer:
+        <select id="sleep">
+            <option value="0">Off</option>
+            <option value="15">15 minutes</option>
+            <option value="30">30 minutes</option>
+            <option value="45">45 minutes</option>
+            <option value="60">1 hour</option>
+            <option value="90">1.5 hours</option>
+        </select>
+        <span id="sleep-status"></span>
+    </p>
+    <script>
+        const video = document.querySelector("video");
+
+        // Remuxed streams start at 0 wherever they were asked to start, and
+        // seek by asking for another start
+        let remuxed = {remuxed};
+        const jump = document.getElementById("jump");
+        const position = document.getElementById("position");
+        function offset() {{
+            if (!remuxed) return 0;
+            const seconds = Number(new URL(video.src).searchParams.get("{seek}"));
+            return seconds > 0 ? seconds : 0;
+        }}
+        function clock(seconds) {{
+            const s = Math.floor(seconds);
+            const pad = (n) => String(n).padStart(2, "0");
+            return Math.floor(s / 3600) + ":" + pad(Math.floor(s / 60) % 60) + ":" + pad(s % 60);
+        }}
+        document.getElementById("jump-go").addEventListener("click", () => {{
+            const parts = document.getElementById("jump-to").value.split(":").map(Number);
+            if (parts.some((part) => !(part >= 0))) return;
+            flush();
+            last = null;
+            const url = new URL(video.src);
+            url.searchParams.set("{seek}", parts.reduce((total, part) => total * 60 + part, 0));
+            video.src = url.pathname + url.search;
+            video.play();
+        }});
+
+        // Unsupported containers fail with MEDIA_ERR_DECODE or
+        // MEDIA_ERR_SRC_NOT_SUPPORTED; try once more as MP4
+        const fallback = video.dataset.fallback;
+        let fellBack = false;
+        video.addEventListener("error", () => {{
+            if (!fallback || fellBack || !video.error || video.error.code < 3) return;
+            fellBack = true;
+            video.src = fallback;
+            video.play();
+            remuxed = true;
+            jump.hidden = false;
+        }});
+
+        // Report the ranges played continuously since the last beacon
+        let last = null;
+        let pending = [];
+        video.addEventListener("timeupdate", () => {{
+            const now = offset() + video.currentTime;
+            if (remuxed) position.textContent = "at " + clock(now);
+            if (last !== null && now > last && now - last < 2) {{
+                const range = pending[pending.length - 1];
+                if (range && range[1] === last) {{
+                    range[1] = now;
+                }} else {{
+                    pending.push([last, now]);
+                }}
+            }}
+            last = now;
+        }});
+        video.addEventListener("seeking", () => {{ last = null; }});
+        function flush() {{
+            if (pending.length === 0) return;
+            navigator.sendBeacon("{beacon}", JSON.stringify({{ video: {alias}, ranges: pending }}));
+            pending = [];
+        }}
+        setInterval(flush, 15000);
+        addEventListener("pagehide", flush);
+
+        // Pause once the sleep timer runs out; the server closes the idle
+        // stream after a while
+        const sleep = document.getElementById("sleep");
+        const sleepStatus = document.getElementById("sleep-status");
+        let sleepAt = null;
+        sleep.addEventListener("change", () => {{
+            const minutes = Number(sleep.value);
+            sleepAt = minutes > 0 ? Date.now() + minutes * 60000 : null;
+            sleepStatus.textContent = "";
+        }});
+        setInterval(() => {{
+            if (sleepAt === null) return;
+            const left = sleepAt - Date.now();
+            if (left > 0) {{
+                sleepStatus.textContent = Math.ceil(left / 60000) + " min left";
+                return;
+            }}
+            sleepAt = null;
+            sleep.value = "0";
+            video.pause();
+            flush();
+            sleepStatus.textContent = "Paused by the sleep timer";
+        }}, 1000);
+    </script>
+</body>
+</html>"#,
+        name = http::html_escape(&name),
+        src = http::html_escape(src),
+        beacon = BEACON_PATH,
+        alias = json::quote(&video.alias),
+        fallback = fallback_attr,
+        jump_hidden = if remuxed { "" } else { " hidden" },
+        remuxed = remuxed,
+        seek = SEEK_PARAM,
+    )
+}
@@ -0,0 +1,896 @@
+//! Just enough container parsing to sanity check video files without
+//! decoding them: MP4/QuickTime box trees, Matroska/WebM EBML elements and
+//! the RIFF, FLV and ASF headers.
+// Chapters and audio tracks are only read to remux
+#![cfg_attr(not(feature = "transcoding"), allow(dead_code))]
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+const EBML_HEADER: u32 = 0x1a45_dfa3;
+const SEGMENT: u32 = 0x1853_8067;
+const SEGMENT_INFO: u32 = 0x1549_a966;
+const TRACKS: u32 = 0x1654_ae6b;
+const CLUSTER: u32 = 0x1f43_b675;
+const TIMECODE_SCALE: u32 = 0x2a_d7b1;
+const DURATION: u32 = 0x4489;
+const CHAPTERS: u32 = 0x1043_a770;
+const EDITION_ENTRY: u32 = 0x45b9;
+const CHAPTER_ATOM: u32 = 0xb6;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_TYPE: u32 = 0x83;
+const TRACK_NAME: u32 = 0x536e;
+const CODEC_ID: u32 = 0x86;
+const FLAG_VISUAL_IMPAIRED: u32 = 0x55ab;
+const FLAG_COMMENTARY: u32 = 0x55af;
+
+const MATROSKA_VIDEO_TRACK: u64 = 1;
+const MATROSKA_AUDIO_TRACK: u64 = 2;
+
+/// Matroska codec IDs MP4 can hold as they are; a family such as `A_AAC`
+/// includes its profiles (`A_AAC/MPEG4/LC`, ...).
+const MP4_CODECS: &[&str] = &[
+    "V_MPEG4/ISO/AVC",
+    "V_MPEGH/ISO/HEVC",
+    "V_AV1",
+    "V_VP9",
+    "A_AAC",
+    "A_AC3",
+    "A_EAC3",
+    "A_OPUS",
+    "A_MPEG/L3",
+    "A_FLAC",
+];
+
+const ASF_HEADER_GUID: [u8; 16] = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Mp4,
+    Matroska,
+    Avi,
+    Flv,
+    Asf,
+    Unknown,
+}
+
+pub fn detect(header: &[u8]) -> Format {
+    if header.len() >= 4
+        && u32::from_be_bytes([header[0], header[1], header[2], header[3]]) == EBML_HEADER
+    {
+        Format::Matroska
+    } else if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"AVI " {
+        Format::Avi
+    } else if header.starts_with(b"FLV") {
+        Format::Flv
+    } else if header.starts_with(&ASF_HEADER_GUID) {
+        Format::Asf
+    } else if header.len() >= 8
+        && header[4..8]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b' ')
+    {
+        Format::Mp4
+    } else {
+        Format::Unknown
+    }
+}
+
+/// Checks that the container structure of `path` is intact. The error
+/// describes the first problem found.
+pub fn validate(path: &Path) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    if len == 0 {
+        return Err("empty file".to_string());
+    }
+
+    let mut header = [0u8; 16];
+    let read = read_up_to(&mut file, &mut header).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+
+    match detect(&header[..read]) {
+        Format::Mp4 => validate_mp4(&mut file, len),
+        Format::Matroska => validate_matroska(&mut file, len),
+        Format::Avi => validate_avi(&mut file, len),
+        Format::Flv => validate_flv(&header[..read], len),
+        Format::Asf => validate_asf(&header[..read], &mut file, len),
+        Format::Unknown => Err("unrecognized container format".to_string()),
+    }
+}
+
+/// Reads the running time recorded in the container header, for MP4 and
+/// Matroska files.
+pub fn duration(path: &Path) -> Option<Duration> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut header = [0u8; 16];
+    let read = read_up_to(&mut file, &mut header).ok()?;
+
+    match detect(&header[..read]) {
+        Format::Mp4 => mp4_duration(&mut file, len),
+        Format::Matroska => matroska_duration(&mut file, len),
+        _ => None,
+    }
+}
+
+/// Whether the video and audio streams of `path` can be copied into MP4,
+/// as those of MP4 files always can. Of the others only Matroska files are
+/// looked into: AVI, FLV and ASF files mostly hold codecs browsers can't
+/// decode in any container.
+pub fn fits_mp4(path: &Path) -> bool {
+    let Ok(mut file) = File::open(path) else {
+        return false;
+    };
+    let Ok(len) = file.metadata().map(|m| m.len()) else {
+        return false;
+    };
+    let mut header = [0u8; 16];
+    let Ok(read) = read_up_to(&mut file, &mut header) else {
+        return false;
+    };
+    match detect(&header[..read]) {
+        Format::Mp4 => return true,
+        Format::Matroska => {}
+        _ => return false,
+    }
+
+    matroska_codecs(&mut file, len).is_some_and(|codecs| {
+        !codecs.is_empty()
+            && codecs.iter().all(|codec| {
+                MP4_CODECS.iter().any(|fits| {
+                    codec
+                        .strip_prefix(fits)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                })
+            })
+    })
+}
+
+/// A chapter as recorded in the container.
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// Reads the chapter list of an MKV file (first edition) or an MP4 file
+/// (Nero `chpl` chapters), sorted by start. Chapters without a recorded end
+/// run until the next one, the last until the end of the file.
+pub fn chapters(path: &Path) -> Vec<Chapter> {
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let Ok(len) = file.metadata().map(|m| m.len()) else {
+        return Vec::new();
+    };
+    let mut header = [0u8; 16];
+    let Ok(read) = read_up_to(&mut file, &mut header) else {
+        return Vec::new();
+    };
+
+    let (marks, duration) = match detect(&header[..read]) {
+        Format::Mp4 => (mp4_chapters(&mut file, len), mp4_duration(&mut file, len)),
+        Format::Matroska => (
+            matroska_chapters(&mut file, len),
+            matroska_duration(&mut file, len),
+        ),
+        _ => return Vec::new(),
+    };
+    let mut marks = marks.unwrap_or_default();
+    marks.sort_by_key(|(_, start, _)| *start);
+
+    let mut chapters = Vec::new();
+    for (i, (title, start, end)) in marks.iter().enumerate() {
+        let next = marks.get(i + 1).map(|(_, start, _)| *start);
+        let Some(end) = end.or(next).or(duration) else {
+            continue;
+        };
+        if end > *start {
+            chapters.push(Chapter {
+                title: title.clone(),
+                start: *start,
+                end,
+            });
+        }
+    }
+    chapters
+}
+
+/// An audio track and what its metadata says it carries.
+pub struct AudioTrack {
+    /// Index of the track among all streams of the file, as ffmpeg counts.
+    pub stream: usize,
+    pub commentary: bool,
+    /// Audio description for the visually impaired.
+    pub description: bool,
+}
+
+/// Lists the audio tracks of an MKV or MP4 file. Matroska's commentary and
+/// visually impaired flags are used where set, otherwise the track name
+/// (MP4 handler name) is looked at.
+pub fn audio_tracks(path: &Path) -> Vec<AudioTrack> {
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let Ok(len) = file.metadata().map(|m| m.len()) else {
+        return Vec::new();
+    };
+    let mut header = [0u8; 16];
+    let Ok(read) = read_up_to(&mut file, &mut header) else {
+        return Vec::new();
+    };
+
+    match detect(&header[..read]) {
+        Format::Mp4 => mp4_audio_tracks(&mut file, len),
+        Format::Matroska => matroska_audio_tracks(&mut file, len),
+        _ => None,
+    }
+    .unwrap_or_default()
+}
+
+fn audio_track(stream: usize, name: &str, commentary: bool, description: bool) -> AudioTrack {
+    let name = name.to_lowercase();
+    AudioTrack {
+        stream,
+        commentary: commentary || name.contains("commentary"),
+        description: description
+            || name.contains("audio description")
+            || name.contains("descriptive")
+            || name.contains("described"),
+    }
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn read_array<const N: usize>(file: &mut File) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// An MP4 box header: its type and the offsets of its contents and its end.
+struct Mp4Box {
+    kind: [u8; 4],
+    data: u64,
+    end: u64,
+}
+
+/// Reads the box header at `pos`, checking that the box fits before `limit`.
+fn read_mp4_box(file: &mut File, pos: u64, limit: u64) -> Result<Mp4Box, String> {
+    if limit - pos < 8 {
+        return Err(format!("truncated box header at offset {}", pos));
+    }
+    file.seek(SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
+    let header: [u8; 8] = read_array(file).map_err(|e| e.to_string())?;
+    let kind = [header[4], header[5], header[6], header[7]];
+    if !kind.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        return Err(format!("garbage box type at offset {}", pos));
+    }
+
+    let (size, header_len) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]])
+    {
+        0 => (limit - pos, 8),
+        1 => {
+            let large: [u8; 8] = read_array(file).map_err(|e| e.to_string())?;
+            (u64::from_be_bytes(large), 16)
+        }
+        size => (size as u64, 8),
+    };
+    if size < header_len {
+        return Err(format!("invalid box size at offset {}", pos));
+    }
+    if size > limit - pos {
+        return Err(format!(
+            "'{}' box at offset {} runs past the end of the file (truncated?)",
+            String::from_utf8_lossy(&kind),
+            pos
+        ));
+    }
+
+    Ok(Mp4Box {
+        kind,
+        data: pos + header_len,
+        end: pos + size,
+    })
+}
+
+fn validate_mp4(file: &mut File, len: u64) -> Result<(), String> {
+    let mut pos = 0;
+    let mut has_moov = false;
+    let mut has_media = false;
+
+    while pos < len {
+        // Some muxers pad the end of the file with zeros
+        if len - pos < 8 {
+            break;
+        }
+        let mp4_box = read_mp4_box(file, pos, len)?;
+        match &mp4_box.kind {
+            b"moov" => has_moov = true,
+            b"mdat" | b"moof" => has_media = true,
+            _ => {}
+        }
+        pos = mp4_box.end;
+    }
+
+    if !has_moov {
+        return Err("no 'moov' box: the recording was never finalized".to_string());
+    }
+    if !has_media {
+        return Err("no media data".to_string());
+    }
+    Ok(())
+}
+
+/// Finds the first box of type `kind` between `pos` and `limit`.
+fn find_mp4_box(file: &mut File, mut pos: u64, limit: u64, kind: &[u8; 4]) -> Option<Mp4Box> {
+    while limit - pos >= 8 {
+        let mp4_box = read_mp4_box(file, pos, limit).ok()?;
+        if &mp4_box.kind == kind {
+            return Some(mp4_box);
+        }
+        pos = mp4_box.end;
+    }
+    None
+}
+
+fn read_mp4_box_data(file: &mut File, mp4_box: &Mp4Box, max: u64) -> Option<Vec<u8>> {
+    if mp4_box.end - mp4_box.data > max {
+        return None;
+    }
+    file.seek(SeekFrom::Start(mp4_box.data)).ok()?;
+    let mut data = vec![0; (mp4_box.end - mp4_box.data) as usize];
+    file.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+fn mp4_audio_tracks(file: &mut File, len: u64) -> Option<Vec<AudioTrack>> {
+    let moov = find_mp4_box(file, 0, len, b"moov")?;
+    let mut tracks = Vec::new();
+    let mut stream = 0;
+    let mut pos = moov.data;
+
+    while moov.end - pos >= 8 {
+        let trak = read_mp4_box(file, pos, moov.end).ok()?;
+        pos = trak.end;
+        if &trak.kind != b"trak" {
+            continue;
+        }
+        if let Some(mdia) = find_mp4_box(file, trak.data, trak.end, b"mdia")
+            && let Some(hdlr) = find_mp4_box(file, mdia.data, mdia.end, b"hdlr")
+            && let Some(data) = read_mp4_box_data(file, &hdlr, 4096)
+            && data.get(8..12) == Some(b"soun")
+        {
+            // version, flags, predefined, handler type, reserved, then the
+            // name (QuickTime prefixes it with its length)
+            let name: String = String::from_utf8_lossy(data.get(24..).unwrap_or_default())
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect();
+            tracks.push(audio_track(stream, &name, false, false));
+        }
+        stream += 1;
+    }
+    Some(tracks)
+}
+
+fn mp4_duration(file: &mut File, len: u64) -> Option<Duration> {
+    let moov = find_mp4_box(file, 0, len, b"moov")?;
+    find_mp4_box(file, moov.data, moov.end, b"mvhd")?;
+
+    let version: [u8; 4] = read_array(file).ok()?;
+    let (timescale, duration) = if version[0] == 1 {
+        let fields: [u8; 28] = read_array(file).ok()?;
+        (
+            u32::from_be_bytes(fields[16..20].try_into().unwrap()),
+            u64::from_be_bytes(fields[20..28].try_into().unwrap()),
+        )
+    } else {
+        let fields: [u8; 16] = read_array(file).ok()?;
+        (
+            u32::from_be_bytes(fields[8..12].try_into().unwrap()),
+            u32::from_be_bytes(fields[12..16].try_into().unwrap()) as u64,
+        )
+    };
+    if timescale == 0 {
+        return None;
+    }
+    // A corrupt header can claim a running time no Duration holds
+    Duration::try_from_secs_f64(duration as f64 / timescale as f64).ok()
+}
+
+/// Chapter titles, starts and (never recorded in MP4) ends.
+type ChapterMarks = Vec<(String, Duration, Option<Duration>)>;
+
+fn mp4_chapters(file: &mut File, len: u64) -> Option<ChapterMarks> {
+    let moov = find_mp4_box(file, 0, len, b"moov")?;
+    let udta = find_mp4_box(file, moov.data, moov.end, b"udta")?;
+    let chpl = find_mp4_box(file, udta.data, udta.end, b"chpl")?;
+    let data = read_mp4_box_data(file, &chpl, 64 * 1024)?;
+    // version, flags, a reserved word in version 1, then the count
+    let mut pos = if data.first()? == &1 { 8 } else { 4 };
+    let count = *data.get(pos)?;
+    pos += 1;
+
+    let mut marks = Vec::new();
+    for _ in 0..count {
+        let start = u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().unwrap());
+        let title_len = *data.get(pos + 8)? as usize;
+        let title = data.get(pos + 9..pos + 9 + title_len)?;
+        pos += 9 + title_len;
+        // 100 ns units
+        marks.push((
+            String::from_utf8_lossy(title).into_owned(),
+            Duration::from_nanos(start.saturating_mul(100)),
+            None,
+        ));
+    }
+    Some(marks)
+}
+
+/// Reads an EBML variable length integer. IDs keep their length marker,
+/// sizes drop it; an all-ones size means "unknown" and is returned as `None`.
+fn read_vint(file: &mut File, keep_marker: bool) -> Result<(Option<u64>, u64), String> {
+    let first: [u8; 1] = read_array(file).map_err(|_| "unexpected end of file".to_string())?;
+    let first = first[0];
+    if first == 0 {
+        return Err("invalid EBML length".to_string());
+    }
+    let len = first.leading_zeros() as usize + 1;
+
+    let mask = 0xffu64 >> len;
+    let mut value = if keep_marker {
+        first as u64
+    } else {
+        first as u64 & mask
+    };
+    let mut all_ones = first as u64 & mask == mask;
+    for _ in 1..len {
+        let byte: [u8; 1] = read_array(file).map_err(|_| "unexpected end of file".to_string())?;
+        value = (value << 8) | byte[0] as u64;
+        all_ones &= byte[0] == 0xff;
+    }
+
+    if !keep_marker && all_ones {
+        Ok((None, len as u64))
+    } else {
+        Ok((Some(value), len as u64))
+    }
+}
+
+/// An EBML element header: ID, offset of the data and its size, if known.
+struct Element {
+    id: u32,
+    data: u64,
+    size: Option<u64>,
+}
+
+fn read_element(file: &mut File, pos: u64) -> Result<Element, String> {
+    file.seek(SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
+    let (id, id_len) = read_vint(file, true)?;
+    let (size, size_len) = read_vint(file, false)?;
+    Ok(Element {
+        id: id.unwrap_or_default() as u32,
+        data: pos + id_len + size_len,
+        size,
+    })
+}
+
+fn read_element_data(file: &mut File, element: &Element, max: u64) -> Option<Vec<u8>> {
+    let size = element.size.filter(|size| *size <= max)?;
+    file.seek(SeekFrom::Start(element.data)).ok()?;
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data).ok()?;
+    Some(data)
+}
+
+fn read_uint(file: &mut File, element: &Element) -> Option<u64> {
+    let data = read_element_data(file, element, 8)?;
+    Some(data.iter().fold(0, |value, b| (value << 8) | *b as u64))
+}
+
+/// Reads the headers of the elements between `pos` and `end`.
+fn read_children(file: &mut File, mut pos: u64, end: u64) -> Option<Vec<Element>> {
+    let mut children = Vec::new();
+    while pos < end {
+        let element = read_element(file, pos).ok()?;
+        pos = element.data + element.size?;
+        children.push(element);
+    }
+    Some(children)
+}
+
+/// Returns the position and end of the first Segment child with `id`.
+fn find_segment_child(file: &mut File, len: u64, id: u32) -> Option<(u64, u64)> {
+    let header = read_element(file, 0).ok()?;
+    let segment = read_element(file, header.data + header.size?).ok()?;
+    if segment.id != SEGMENT {
+        return None;
+    }
+    let segment_end = segment
+        .size
+        .map_or(len, |size| (segment.data + size).min(len));
+
+    let mut pos = segment.data;
+    while pos < segment_end {
+        let element = read_element(file, pos).ok()?;
+        let end = element.data + element.size?;
+        if element.id == id {
+            return Some((element.data, end.min(segment_end)));
+        }
+        pos = end;
+    }
+    None
+}
+
+fn matroska_duration(file: &mut File, len: u64) -> Option<Duration> {
+    let (mut pos, end) = find_segment_child(file, len, SEGMENT_INFO)?;
+    let mut timecode_scale = 1_000_000u64;
+    let mut duration = None;
+
+    while pos < end {
+        let element = read_element(file, pos).ok()?;
+        match element.id {
+            TIMECODE_SCALE => {
+                timecode_scale = read_uint(file, &element)?;
+            }
+            DURATION => {
+                let data = read_element_data(file, &element, 8)?;
+                duration = match data.len() {
+                    4 => Some(f32::from_be_bytes(data.try_into().unwrap()) as f64),
+                    8 => Some(f64::from_be_bytes(data.try_into().unwrap())),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+        pos = element.data + element.size?;
+    }
+
+    let seconds = duration? * timecode_scale as f64 / 1e9;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+fn matroska_chapters(file: &mut File, len: u64) -> Option<ChapterMarks> {
+    let (pos, end) = find_segment_child(file, len, CHAPTERS)?;
+    let edition = read_children(file, pos, end)?
+        .into_iter()
+        .find(|element| element.id == EDITION_ENTRY)?;
+
+    let mut marks = Vec::new();
+    let atoms = read_children(file, edition.data, edition.data + edition.size?)?;
+    for atom in atoms.iter().filter(|element| element.id == CHAPTER_ATOM) {
+        let mut title = String::new();
+        let mut start = None;
+        let mut end = None;
+        let mut hidden = false;
+        for element in read_children(file, atom.data, atom.data + atom.size?)? {
+            match element.id {
+                CHAPTER_TIME_START => start = read_uint(file, &element),
+                CHAPTER_TIME_END => end = read_uint(file, &element),
+                CHAPTER_FLAG_HIDDEN => hidden = read_uint(file, &element) == Some(1),
+                CHAPTER_DISPLAY if title.is_empty() => {
+                    let display = read_children(file, element.data, element.data + element.size?)?;
+                    if let Some(string) = display.iter().find(|e| e.id == CHAP_STRING)
+                        && let Some(data) = read_element_data(file, string, 4096)
+                    {
+                        title = String::from_utf8_lossy(&data).into_owned();
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Nanoseconds, regardless of the timecode scale
+        if let Some(start) = start
+            && !hidden
+        {
+            marks.push((
+                title,
+                Duration::from_nanos(start),
+                end.map(Duration::from_nanos),
+            ));
+        }
+    }
+    Some(marks)
+}
+
+fn matroska_audio_tracks(file: &mut File, len: u64) -> Option<Vec<AudioTrack>> {
+    let (pos, end) = find_segment_child(file, len, TRACKS)?;
+    let entries = read_children(file, pos, end)?;
+
+    let mut tracks = Vec::new();
+    for (stream, entry) in entries
+        .iter()
+        .filter(|element| element.id == TRACK_ENTRY)
+        .enumerate()
+    {
+        let mut audio = false;
+        let mut name = String::new();
+        let mut commentary = false;
+        let mut description = false;
+        for element in read_children(file, entry.data, entry.data + entry.size?)? {
+            match element.id {
+                TRACK_TYPE => audio = read_uint(file, &element) == Some(MATROSKA_AUDIO_TRACK),
+                TRACK_NAME => {
+                    if let Some(data) = read_element_data(file, &element, 4096) {
+                        name = String::from_utf8_lossy(&data).into_owned();
+                    }
+                }
+                FLAG_COMMENTARY => commentary = read_uint(file, &element) == Some(1),
+                FLAG_VISUAL_IMPAIRED => description = read_uint(file, &element) == Some(1),
+                _ => {}
+            }
+        }
+        if audio {
+            tracks.push(audio_track(stream, &name, commentary, description));
+        }
+    }
+    Some(tracks)
+}
+
+/// The codec IDs of the video and audio tracks.
+fn matroska_codecs(file: &mut File, len: u64) -> Option<Vec<String>> {
+    let (pos, end) = find_segment_child(file, len, TRACKS)?;
+    let entries = read_children(file, pos, end)?;
+
+    let mut codecs = Vec::new();
+    for entry in entries.iter().filter(|element| element.id == TRACK_ENTRY) {
+        let mut kind = None;
+        let mut codec = String::new();
+        for element in read_children(file, entry.data, entry.data + entry.size?)? {
+            match element.id {
+                TRACK_TYPE => kind = read_uint(file, &element),
+                CODEC_ID => {
+                    if let Some(data) = read_element_data(file, &element, 256) {
+                        codec = String::from_utf8_lossy(&data).into_owned();
+                    }
+                }
+                _ => {}
+            }
+        }
+        if matches!(kind, Some(MATROSKA_VIDEO_TRACK | MATROSKA_AUDIO_TRACK)) {
+            codecs.push(codec);
+        }
+    }
+    Some(codecs)
+}
+
+fn validate_matroska(file: &mut File, len: u64) -> Result<(), String> {
+    let header = read_element(file, 0)?;
+    let header_end = header.data + header.size.ok_or("EBML header has unknown size")?;
+
+    let segment = read_element(file, header_end)?;
+    if segment.id != SEGMENT {
+        return Err("no Segment after the EBML header".to_string());
+    }
+    let segment_end = match segment.size {
+        Some(size) if segment.data + size > len => {
+            return Err(format!(
+                "Segment claims {} bytes but the file ends after {} (truncated?)",
+                size,
+                len - segment.data
+            ));
+        }
+        Some(size) => segment.data + size,
+        None => len,
+    };
+
+    let mut pos = segment.data;
+    let mut has_info = false;
+    let mut has_tracks = false;
+    let mut has_cluster = false;
+    while pos < segment_end {
+        let element = read_element(file, pos)?;
+        match element.id {
+            SEGMENT_INFO => has_info = true,
+            TRACKS => has_tracks = true,
+            CLUSTER => has_cluster = true,
+            _ => {}
+        }
+        match element.size {
+            Some(size) if element.data + size > segment_end => {
+                return Err(format!(
+                    "element {:#x} at offset {} runs past the end of the segment (truncated?)",
+                    element.id, pos
+                ));
+            }
+            Some(size) => pos = element.data + size,
+            // Live recordings leave clusters unsized; nothing further to walk
+            None => break,
+        }
+    }
+
+    if !has_info {
+        return Err("no segment Info".to_string());
+    }
+    if !has_tracks {
+        return Err("no Tracks".to_string());
+    }
+    if !has_cluster {
+        return Err("no media clusters".to_string());
+    }
+    Ok(())
+}
+
+fn validate_avi(file: &mut File, len: u64) -> Result<(), String> {
+    let header: [u8; 24] = read_array(file).map_err(|_| "truncated RIFF header".to_string())?;
+    let riff_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    if riff_size + 8 > len {
+        return Err(format!(
+            "RIFF claims {} bytes but the file has {} (truncated?)",
+            riff_size + 8,
+            len
+        ));
+    }
+    if &header[12..16] != b"LIST" || &header[20..24] != b"hdrl" {
+        return Err("missing AVI header list".to_string());
+    }
+    Ok(())
+}
+
+fn validate_flv(header: &[u8], len: u64) -> Result<(), String> {
+    if header.len() < 9 || header[3] != 1 {
+        return Err("unsupported FLV version".to_string());
+    }
+    let data_offset = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as u64;
+    if data_offset < 9 || data_offset + 4 > len {
+        return Err("FLV header is damaged".to_string());
+    }
+    if data_offset + 4 == len {
+        return Err("no media data".to_string());
+    }
+    Ok(())
+}
+
+fn validate_asf(header: &[u8], file: &mut File, len: u64) -> Result<(), String> {
+    file.seek(SeekFrom::Start(header.len() as u64))
+        .map_err(|e| e.to_string())?;
+    let size: [u8; 8] = read_array(file).map_err(|_| "truncated ASF header".to_string())?;
+    let size = u64::from_le_bytes(size);
+    if size > len {
+        return Err("ASF header runs past the end of the file (truncated?)".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::temp_file;
+
+    /// A `moov` box holding only a version 1 `mvhd`.
+    fn mp4(timescale: u32, duration: u64) -> Vec<u8> {
+        let mut mvhd = Vec::new();
+        mvhd.extend_from_slice(&40u32.to_be_bytes());
+        mvhd.extend_from_slice(b"mvhd");
+        mvhd.extend_from_slice(&[1, 0, 0, 0]);
+        mvhd.extend_from_slice(&[0; 16]);
+        mvhd.extend_from_slice(&timescale.to_be_bytes());
+        mvhd.extend_from_slice(&duration.to_be_bytes());
+
+        let mut moov = Vec::new();
+        moov.extend_from_slice(&(8 + mvhd.len() as u32).to_be_bytes());
+        moov.extend_from_slice(b"moov");
+        moov.extend_from_slice(&mvhd);
+        moov
+    }
+
+    /// An empty EBML header and a Segment whose Info holds only `duration`,
+    /// in the default 1 ms timecode scale.
+    fn matroska(duration: f64) -> Vec<u8> {
+        let mut info = vec![0x44, 0x89, 0x88];
+        info.extend_from_slice(&duration.to_be_bytes());
+
+        let mut segment = vec![0x15, 0x49, 0xa9, 0x66, 0x80 | info.len() as u8];
+        segment.extend_from_slice(&info);
+
+        let mut file = vec![0x1a, 0x45, 0xdf, 0xa3, 0x80];
+        file.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x80 | segment.len() as u8]);
+        file.extend_from_slice(&segment);
+        file
+    }
+
+    /// An empty EBML header and a Segment whose Tracks hold a track of each
+    /// type and codec ID.
+    fn matroska_tracks(tracks: &[(u8, &str)]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (kind, codec) in tracks {
+            let mut entry = vec![0x83, 0x81, *kind, 0x86, 0x80 | codec.len() as u8];
+            entry.extend_from_slice(codec.as_bytes());
+            entries.extend_from_slice(&[0xae, 0x80 | entry.len() as u8]);
+            entries.extend_from_slice(&entry);
+        }
+
+        let mut segment = vec![0x16, 0x54, 0xae, 0x6b, 0x80 | entries.len() as u8];
+        segment.extend_from_slice(&entries);
+
+        let mut file = vec![0x1a, 0x45, 0xdf, 0xa3, 0x80];
+        file.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x80 | segment.len() as u8]);
+        file.extend_from_slice(&segment);
+        file
+    }
+
+    #[test]
+    fn tells_which_matroska_files_fit_mp4() {
+        for (name, tracks, fits) in [
+            (
+                "h264-aac.mkv",
+                &[(1, "V_MPEG4/ISO/AVC"), (2, "A_AAC/MPEG4/LC")][..],
+                true,
+            ),
+            (
+                "hevc-subs.mkv",
+                &[(1, "V_MPEGH/ISO/HEVC"), (2, "A_EAC3"), (17, "S_TEXT/ASS")][..],
+                true,
+            ),
+            ("vorbis.mkv", &[(1, "V_VP9"), (2, "A_VORBIS")][..], false),
+            (
+                "pcm.mkv",
+                &[(1, "V_MPEG4/ISO/AVC"), (2, "A_PCM/INT/LIT")][..],
+                false,
+            ),
+            ("aac-like.mkv", &[(2, "A_AACX")][..], false),
+            ("no-tracks.mkv", &[][..], false),
+        ] {
+            let path = temp_file(name, matroska_tracks(tracks));
+            assert_eq!(fits_mp4(&path), fits, "{}", name);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_mp4_duration() {
+        let path = temp_file("duration.mp4", mp4(1000, 90_500));
+        assert_eq!(duration(&path), Some(Duration::from_millis(90_500)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ignores_mp4_duration_out_of_range() {
+        let path = temp_file("huge.mp4", mp4(1, u64::MAX));
+        assert_eq!(duration(&path), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_matroska_duration() {
+        let path = temp_file("duration.mkv", matroska(90_500.0));
+        assert_eq!(duration(&path), Some(Duration::from_millis(90_500)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ignores_matroska_duration_out_of_range() {
+        for (name, seconds) in [
+            ("huge.mkv", 1e300),
+            ("nan.mkv", f64::NAN),
+            ("negative.mkv", -1.0),
+        ] {
+            let path = temp_file(name, matroska(seconds));
+            assert_eq!(duration(&path), None, "{}", name);
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
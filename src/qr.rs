@@ -0,0 +1,475 @@
+//! A minimal QR code encoder: byte mode, error correction level M, versions
+//! 1 to 10 (up to 213 bytes), which is plenty for URLs.
+
+const MAX_VERSION: usize = 10;
+
+/// Error correction codewords per block and number of blocks, by version,
+/// for level M.
+const ECC_PER_BLOCK: [usize; MAX_VERSION + 1] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26];
+const BLOCKS: [usize; MAX_VERSION + 1] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5];
+
+/// Format information bits of level M.
+const LEVEL_M: u32 = 0b00;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `data`, or returns `None` if it is too long.
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let version = (1..=MAX_VERSION).find(|&version| {
+            let count_bits = if version <= 9 { 8 } else { 16 };
+            4 + count_bits + data.len() * 8 <= data_codewords(version) * 8
+        })?;
+
+        let mut qr = QrCode {
+            size: version * 4 + 17,
+            modules: Vec::new(),
+        };
+        qr.modules = vec![false; qr.size * qr.size];
+        let mut function = vec![false; qr.size * qr.size];
+        qr.draw_function_patterns(version, &mut function);
+
+        let codewords = add_error_correction(version, &data_bits(version, data));
+        qr.draw_codewords(&codewords, &function);
+
+        // Pick the mask leaving the fewest patterns that confuse readers
+        let best = (0..8)
+            .min_by_key(|&mask| {
+                qr.apply_mask(mask, &function);
+                qr.draw_format_bits(mask, &mut function);
+                let penalty = qr.penalty();
+                qr.apply_mask(mask, &function);
+                penalty
+            })
+            .unwrap();
+        qr.apply_mask(best, &function);
+        qr.draw_format_bits(best, &mut function);
+        Some(qr)
+    }
+
+    /// Renders the code as SVG, with the quiet zone around it.
+    pub fn to_svg(&self) -> String {
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + 4, y + 4));
+                }
+            }
+        }
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {0} {0}" shape-rendering="crispEdges"><rect width="{0}" height="{0}" fill="#fff"/><path d="{1}" fill="#000"/></svg>"##,
+            self.size + 8,
+            path
+        )
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set(&mut self, function: &mut [bool], x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize, function: &mut [bool]) {
+        let size = self.size;
+        for i in 0..size {
+            self.set(function, 6, i, i % 2 == 0);
+            self.set(function, i, 6, i % 2 == 0);
+        }
+
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4_isize {
+                for dx in -4..=4_isize {
+                    let (xx, yy) = (x as isize + dx, y as isize + dy);
+                    if (0..size as isize).contains(&xx) && (0..size as isize).contains(&yy) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set(
+                            function,
+                            xx as usize,
+                            yy as usize,
+                            distance != 2 && distance != 4,
+                        );
+                    }
+                }
+            }
+        }
+
+        let positions = alignment_positions(version);
+        for &x in &positions {
+            for &y in &positions {
+                let on_finder = (x == 6 && (y == 6 || y == size - 7)) || (x == size - 7 && y == 6);
+                if on_finder {
+                    continue;
+                }
+                for dy in -2..=2_isize {
+                    for dx in -2..=2_isize {
+                        let distance = dx.abs().max(dy.abs());
+                        let (xx, yy) = ((x as isize + dx) as usize, (y as isize + dy) as usize);
+                        self.set(function, xx, yy, distance != 1);
+                    }
+                }
+            }
+        }
+
+        // Reserve the format areas; the real bits are drawn once the mask
+        // is chosen
+        self.draw_format_bits(0, function);
+
+        if version >= 7 {
+            let mut remainder = version as u32;
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+            }
+            let bits = (version as u32) << 12 | remainder;
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set(function, a, b, dark);
+                self.set(function, b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32, function: &mut [bool]) {
+        let data = LEVEL_M << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+
+        for i in 0..6 {
+            self.set(function, 8, i, bit(i));
+        }
+        self.set(function, 8, 7, bit(6));
+        self.set(function, 8, 8, bit(7));
+        self.set(function, 7, 8, bit(8));
+        for i in 9..15 {
+            self.set(function, 14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set(function, size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set(function, 8, size - 15 + i, bit(i));
+        }
+        self.set(function, 8, size - 8, true);
+    }
+
+    /// Fills the non-function modules with `codewords`, in the zigzag order
+    /// of two-module columns from the bottom right.
+    fn draw_codewords(&mut self, codewords: &[u8], function: &[bool]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Flips the data modules selected by `mask`; applying it twice undoes it.
+    fn apply_mask(&mut self, mask: u32, function: &[bool]) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if flip && !function[y * self.size + x] {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// Scores long runs, 2x2 blocks, finder-like patterns and imbalance
+    /// between dark and light modules, as the standard's mask evaluation.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+
+        for transposed in [false, true] {
+            let at = |a: usize, b: usize| {
+                if transposed {
+                    self.get(a, b)
+                } else {
+                    self.get(b, a)
+                }
+            };
+            for a in 0..size {
+                let mut run = 1;
+                for b in 1..size {
+                    if at(a, b) == at(a, b - 1) {
+                        run += 1;
+                        if run == 5 {
+                            penalty += 3;
+                        } else if run > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run = 1;
+                    }
+                }
+
+                const FINDER: [bool; 11] = [
+                    true, false, true, true, true, false, true, false, false, false, false,
+                ];
+                for b in 0..=size.saturating_sub(FINDER.len()) {
+                    let matches = |reversed: bool| {
+                        (0..FINDER.len()).all(|k| {
+                            let expected = if reversed {
+                                FINDER[FINDER.len() - 1 - k]
+                            } else {
+                                FINDER[k]
+                            };
+                            at(a, b + k) == expected
+                        })
+                    };
+                    if matches(false) || matches(true) {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.get(x, y);
+                if dark == self.get(x + 1, y)
+                    && dark == self.get(x, y + 1)
+                    && dark == self.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let total = size * size;
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty + deviation.div_ceil(total).saturating_sub(1) * 10
+    }
+}
+
+/// Number of codewords a version holds, data and error correction.
+fn raw_codewords(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules / 8
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_codewords(version) - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = (version * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// The data codewords: byte mode header, `data`, terminator and padding.
+fn data_bits(version: usize, data: &[u8]) -> Vec<u8> {
+    let capacity = data_codewords(version) * 8;
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity);
+    let mut push = |value: usize, count: usize| {
+        for i in (0..count).rev() {
+            bits.push((value >> i) & 1 == 1);
+        }
+    };
+    push(0b0100, 4);
+    push(data.len(), if version <= 9 { 8 } else { 16 });
+    for &byte in data {
+        push(byte as usize, 8);
+    }
+
+    let terminator = (capacity - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    while !bits.len().is_multiple_of(8) {
+        bits.push(false);
+    }
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+        .collect();
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if codewords.len() * 8 >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+/// Splits the data into blocks, appends each block's Reed-Solomon codewords
+/// and interleaves the result.
+fn add_error_correction(version: usize, data: &[u8]) -> Vec<u8> {
+    let blocks = BLOCKS[version];
+    let ecc_len = ECC_PER_BLOCK[version];
+    let raw = raw_codewords(version);
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks - ecc_len;
+    let divisor = reed_solomon_divisor(ecc_len);
+
+    let mut data_blocks = Vec::with_capacity(blocks);
+    let mut ecc_blocks = Vec::with_capacity(blocks);
+    let mut offset = 0;
+    for i in 0..blocks {
+        let len = short_len + usize::from(i >= short_blocks);
+        let block = &data[offset..offset + len];
+        offset += len;
+        ecc_blocks.push(reed_solomon_remainder(block, &divisor));
+        data_blocks.push(block);
+    }
+
+    let mut codewords = Vec::with_capacity(raw);
+    for i in 0..=short_len {
+        for block in &data_blocks {
+            if let Some(&byte) = block.get(i) {
+                codewords.push(byte);
+            }
+        }
+    }
+    for i in 0..ecc_len {
+        for block in &ecc_blocks {
+            codewords.push(block[i]);
+        }
+    }
+    codewords
+}
+
+/// Multiplies in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1.
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+    z as u8
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (value, &coefficient) in result.iter_mut().zip(divisor) {
+            *value ^= gf_multiply(coefficient, factor);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_a_short_url() {
+        // Version 1-M, mask 6; checked with an independent decoder
+        let expected = [
+            "#######.#.....#######",
+            "#.....#.#.....#.....#",
+            "#.###.#.#.##..#.###.#",
+            "#.###.#...#...#.###.#",
+            "#.###.#.#####.#.###.#",
+            "#.....#..##...#.....#",
+            "#######.#.#.#.#######",
+            ".........##..........",
+            "#..######.##.#..#.###",
+            "..###...###.####.###.",
+            ".#.#..##.####.#..####",
+            "####...##.##.#.#..#..",
+            "##..#.###..........#.",
+            "........###...#.####.",
+            "#######.#..######.#..",
+            "#.....#.#..##.##.####",
+            "#.###.#.#.###...#..#.",
+            "#.###.#.####.##..##..",
+            "#.###.#..##.##.######",
+            "#.....#..##.#.#.#.###",
+            "#######.####.###.#...",
+        ];
+        let qr = QrCode::encode(b"http://tv.lan").unwrap();
+        let rows: Vec<String> = (0..qr.size)
+            .map(|y| {
+                (0..qr.size)
+                    .map(|x| if qr.get(x, y) { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn refuses_data_past_version_10() {
+        assert!(QrCode::encode(&[b'a'; 213]).is_some());
+        assert!(QrCode::encode(&[b'a'; 214]).is_none());
+    }
+}
@@ -0,0 +1,235 @@
+//! Serves videos changed on the fly by remuxing them through ffmpeg, without
+//! re-encoding: cut to a time range, with unwanted audio tracks left out
+//! and/or rewrapped as MP4 for browsers that can't play the original.
+
+use hyper::Request;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+
+use crate::episodes::Clip;
+use crate::probe::{self, AudioTrack};
+use crate::{VideoEntry, http};
+
+/// Query parameter, and cookie, holding the viewer's [`AudioFilter`].
+pub const AUDIO_FILTER_PARAM: &str = "drop_audio";
+pub const AUDIO_FILTER_COOKIE: &str = "streamshit_drop_audio";
+
+/// Query parameter asking for another container; `mp4` is the only one.
+pub const FORMAT_PARAM: &str = "format";
+
+/// Query parameter with the seconds into the video to start at. Remuxed
+/// streams can't be seeked by byte range, so players seek by asking again.
+pub const SEEK_PARAM: &str = "t";
+
+/// Kinds of audio tracks the viewer does not want sent.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct AudioFilter {
+    pub commentary: bool,
+    pub description: bool,
+}
+
+impl AudioFilter {
+    /// Parses a comma separated list of `commentary` and `description`.
+    pub fn parse(value: &str) -> AudioFilter {
+        let kinds: Vec<&str> = value.split(',').map(str::trim).collect();
+        AudioFilter {
+            commentary: kinds.contains(&"commentary"),
+            description: kinds.contains(&"description"),
+        }
+    }
+
+    /// The filter from the request's query, falling back to the cookie.
+    pub fn of<B>(req: &Request<B>) -> AudioFilter {
+        match http::parse_query(req).get(AUDIO_FILTER_PARAM) {
+            Some(value) => AudioFilter::parse(value),
+            None => AudioFilter::parse(&http::cookie(req, AUDIO_FILTER_COOKIE).unwrap_or_default()),
+        }
+    }
+
+    pub fn as_param(&self) -> String {
+        let mut kinds = Vec::new();
+        if self.commentary {
+            kinds.push("commentary");
+        }
+        if self.description {
+            kinds.push("description");
+        }
+        kinds.join(",")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == AudioFilter::default()
+    }
+
+    /// The query string for links, empty without a filter. Players opening
+    /// the links don't send our cookies, so the filter travels in the query.
+    pub fn query(&self) -> String {
+        if self.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "?{}={}",
+                AUDIO_FILTER_PARAM,
+                http::percent_encode(&self.as_param())
+            )
+        }
+    }
+
+    /// The catalog's picker for the filter, with this one selected.
+    pub fn generate_form_html(&self) -> String {
+        let mut html = format!(
+            r#"<form class="audio-filter" method="get" action="/">Audio: <select name="{}" onchange="this.form.submit()">"#,
+            AUDIO_FILTER_PARAM
+        );
+        for (value, label) in [
+            ("", "All tracks"),
+            ("commentary", "Skip commentary"),
+            ("description", "Skip audio description"),
+            (
+                "commentary,description",
+                "Skip commentary and audio description",
+            ),
+        ] {
+            html.push_str(&format!(
+                "<option value=\"{}\"{}>{}</option>",
+                value,
+                if self.as_param() == value {
+                    " selected"
+                } else {
+                    ""
+                },
+                label
+            ));
+        }
+        html.push_str("</select> <noscript><button>Apply</button></noscript></form>");
+        html
+    }
+
+    /// The streams of `tracks` to leave out. A file is never left without
+    /// audio: if every track matches, none is dropped.
+    pub fn streams_to_drop(&self, tracks: &[AudioTrack]) -> Vec<usize> {
+        let dropped: Vec<usize> = tracks
+            .iter()
+            .filter(|track| {
+                (self.commentary && track.commentary) || (self.description && track.description)
+            })
+            .map(|track| track.stream)
+            .collect();
+        if dropped.len() == tracks.len() {
+            return Vec::new();
+        }
+        dropped
+    }
+}
+
+/// What to change about a file.
+#[derive(Clone)]
+pub struct Remux {
+    pub clip: Option<Clip>,
+    /// Stream indexes to leave out.
+    pub drop_streams: Vec<usize>,
+    /// Write fragmented MP4 instead of Matroska, which Safari plays in a
+    /// plain `<video>` and which needs no seeking to start. Only streams
+    /// MP4 can hold survive the copy; see [`probe::fits_mp4`].
+    pub mp4: bool,
+    /// Where to start, into the clip if there is one.
+    pub seek: Duration,
+}
+
+impl Remux {
+    /// What the request for `video` asks to change.
+    pub fn of<B>(req: &Request<B>, video: &VideoEntry) -> Remux {
+        let audio_filter = AudioFilter::of(req);
+        let query = http::parse_query(req);
+        Remux {
+            clip: video.episode.as_ref().map(|episode| episode.clip.clone()),
+            drop_streams: if audio_filter.is_empty() {
+                Vec::new()
+            } else {
+                audio_filter.streams_to_drop(&probe::audio_tracks(&video.path))
+            },
+            mp4: query
+                .get(FORMAT_PARAM)
+                .is_some_and(|format| format == "mp4"),
+            seek: query
+                .get(SEEK_PARAM)
+                .and_then(|seconds| seconds.parse().ok())
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the file can be sent as it is. A seek alone doesn't count,
+    /// the whole file seeks by byte range.
+    pub fn is_noop(&self) -> bool {
+        self.clip.is_none() && self.drop_streams.is_empty() && !self.mp4
+    }
+
+    /// Content type of the remuxed output.
+    pub fn mime_type(&self) -> &'static str {
+        if self.mp4 {
+            "video/mp4"
+        } else {
+            "video/x-matroska"
+        }
+    }
+}
+
+/// Starts ffmpeg writing the remuxed `path` to its stdout.
+///
+/// Stream copy can only cut at keyframes, so a clip or seek may start
+/// slightly before its start.
+pub fn spawn(ffmpeg: &str, path: &Path, remux: &Remux) -> io::Result<Child> {
+    let clip_start = remux
+        .clip
+        .as_ref()
+        .map(|clip| clip.start)
+        .unwrap_or_default();
+    let start = clip_start.saturating_add(remux.seek);
+
+    let mut command = Command::new(ffmpeg);
+    command.args(["-nostdin", "-v", "error"]);
+    if !start.is_zero() {
+        command
+            .arg("-ss")
+            .arg(format!("{:.3}", start.as_secs_f64()));
+    }
+    command.arg("-i").arg(path);
+    if let Some(clip) = &remux.clip {
+        command.arg("-t").arg(format!(
+            "{:.3}",
+            clip.end.saturating_sub(start).as_secs_f64()
+        ));
+    }
+    if remux.mp4 {
+        // MP4 has no room for Matroska subtitles, fonts or data streams
+        command.args(["-map", "0:v?", "-map", "0:a?"]);
+    } else {
+        command.args(["-map", "0"]);
+    }
+    for stream in &remux.drop_streams {
+        command.arg("-map").arg(format!("-0:{}", stream));
+    }
+
+    command.args(["-c", "copy"]);
+    if remux.mp4 {
+        command.args([
+            "-f",
+            "mp4",
+            "-movflags",
+            "frag_keyframe+empty_moov+default_base_moof",
+        ]);
+    } else {
+        command.args(["-f", "matroska"]);
+    }
+    command
+        .arg("pipe:1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+}
@@ -0,0 +1,308 @@
+//! The route table: every path the server answers, matched segment by
+//! segment against a tree built once at startup.
+
+use std::sync::LazyLock;
+
+use hyper::Method;
+
+use crate::api::Endpoint;
+use crate::kids;
+
+/// Most parameters a pattern may have.
+const MAX_PARAMS: usize = 2;
+
+/// A `{name}` segment of a matched path, kept as its position so routes
+/// stay `Copy` and don't borrow the request.
+#[derive(Clone, Copy, Default)]
+pub struct PathParam {
+    start: usize,
+    end: usize,
+}
+
+impl PathParam {
+    /// The parameter's text in the `path` it was matched against.
+    pub fn get(self, path: &str) -> &str {
+        &path[self.start..self.end]
+    }
+}
+
+/// The parameters of a match, in pattern order.
+#[derive(Default)]
+struct Params {
+    params: [PathParam; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    fn get(&self, index: usize) -> PathParam {
+        self.params[..self.len][index]
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Route {
+    Catalog,
+    LoginForm,
+    Login,
+    Logout,
+    SsoLogin,
+    SsoCallback,
+    Connect,
+    Analytics,
+    EnterKidProfile,
+    LeaveKidProfile,
+    Guests,
+    /// Opening a guest link.
+    GuestLogin {
+        token: PathParam,
+    },
+    Watch {
+        alias: PathParam,
+    },
+    /// A video by alias or file name.
+    Video {
+        name: PathParam,
+    },
+    Api(Endpoint),
+}
+
+type Handler = fn(&Params) -> Route;
+
+static ROUTES: LazyLock<RouteTable> = LazyLock::new(|| {
+    let mut table = RouteTable::default();
+    table.insert(Method::GET, "/", |_| Route::Catalog);
+    table.insert(Method::GET, "/login", |_| Route::LoginForm);
+    table.insert(Method::POST, "/login", |_| Route::Login);
+    table.insert(Method::GET, "/logout", |_| Route::Logout);
+    table.insert(Method::GET, "/auth/login", |_| Route::SsoLogin);
+    table.insert(Method::GET, "/auth/callback", |_| Route::SsoCallback);
+    table.insert(Method::GET, "/connect", |_| Route::Connect);
+    table.insert(Method::GET, "/admin/analytics", |_| Route::Analytics);
+    table.insert(Method::GET, kids::ENTER_PATH, |_| Route::EnterKidProfile);
+    table.insert(Method::GET, kids::LEAVE_PATH, |_| Route::LeaveKidProfile);
+    table.insert(Method::GET, "/admin/guests", |_| Route::Guests);
+    table.insert(Method::GET, "/guest/{token}", |params| Route::GuestLogin {
+        token: params.get(0),
+    });
+    table.insert(Method::GET, "/watch/{alias}", |params| Route::Watch {
+        alias: params.get(0),
+    });
+    table.insert(Method::GET, "/{name}", |params| Route::Video {
+        name: params.get(0),
+    });
+
+    table.insert(Method::GET, "/api/videos", |_| Route::Api(Endpoint::Videos));
+    table.insert(Method::POST, "/api/rescan", |_| {
+        Route::Api(Endpoint::Rescan)
+    });
+    table.insert(Method::GET, "/api/keys", |_| Route::Api(Endpoint::ListKeys));
+    table.insert(Method::POST, "/api/keys", |_| {
+        Route::Api(Endpoint::CreateKey)
+    });
+    table.insert(Method::DELETE, "/api/keys/{id}", |params| {
+        Route::Api(Endpoint::RevokeKey(params.get(0)))
+    });
+    table.insert(Method::GET, "/api/guests", |_| {
+        Route::Api(Endpoint::ListGuests)
+    });
+    table.insert(Method::POST, "/api/guests", |_| {
+        Route::Api(Endpoint::CreateGuest)
+    });
+    table.insert(Method::DELETE, "/api/guests/{id}", |params| {
+        Route::Api(Endpoint::RevokeGuest(params.get(0)))
+    });
+    table.insert(Method::POST, "/api/beacons", |_| {
+        Route::Api(Endpoint::Beacon)
+    });
+    table.insert(Method::GET, "/api/analytics", |_| {
+        Route::Api(Endpoint::Analytics)
+    });
+    table.insert(Method::GET, "/api/admin/maintenance", |_| {
+        Route::Api(Endpoint::MaintenanceStatus)
+    });
+    table.insert(Method::POST, "/api/admin/maintenance", |_| {
+        Route::Api(Endpoint::StartMaintenance)
+    });
+    table.insert(Method::DELETE, "/api/admin/maintenance", |_| {
+        Route::Api(Endpoint::EndMaintenance)
+    });
+    table
+});
+
+/// Finds the route for a request, without allocating.
+pub fn resolve(method: &Method, path: &str) -> Option<Route> {
+    ROUTES.find(method, path)
+}
+
+#[derive(Default)]
+struct Node {
+    statics: Vec<(&'static str, Node)>,
+    param: Option<Box<Node>>,
+    handlers: Vec<(Method, Handler)>,
+}
+
+/// Patterns are `/`-separated segments, each literal or a `{name}`
+/// parameter matching one non-empty segment. Literal segments win over
+/// parameters.
+#[derive(Default)]
+struct RouteTable {
+    root: Node,
+}
+
+impl RouteTable {
+    fn insert(&mut self, method: Method, pattern: &'static str, handler: Handler) {
+        let mut node = &mut self.root;
+        let mut params = 0;
+        for segment in segments(pattern) {
+            node = if segment.starts_with('{') && segment.ends_with('}') {
+                params += 1;
+                assert!(params <= MAX_PARAMS, "too many parameters in {}", pattern);
+                node.param.get_or_insert_default()
+            } else {
+                match node.statics.iter().position(|(s, _)| *s == segment) {
+                    Some(index) => &mut node.statics[index].1,
+                    None => {
+                        node.statics.push((segment, Node::default()));
+                        &mut node.statics.last_mut().unwrap().1
+                    }
+                }
+            };
+        }
+        assert!(
+            !node.handlers.iter().any(|(m, _)| *m == method),
+            "{} {} is routed twice",
+            method,
+            pattern
+        );
+        node.handlers.push((method, handler));
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<Route> {
+        let mut params = Params::default();
+        let node = find_node(&self.root, path, path.strip_prefix('/')?, &mut params)?;
+        let (_, handler) = node.handlers.iter().find(|(m, _)| m == method)?;
+        Some(handler(&params))
+    }
+}
+
+/// The segments of a pattern, `/` itself having none.
+fn segments(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern
+        .strip_prefix('/')
+        .unwrap_or(pattern)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+}
+
+/// Walks down from `node` along `rest`, the unmatched tail of `path`,
+/// backing out of parameters when a literal branch leads nowhere.
+fn find_node<'a>(node: &'a Node, path: &str, rest: &str, params: &mut Params) -> Option<&'a Node> {
+    if rest.is_empty() {
+        return Some(node);
+    }
+    let (segment, tail) = rest.split_once('/').unwrap_or((rest, ""));
+    // A trailing slash leaves an empty segment, which nothing matches
+    if segment.is_empty() || (tail.is_empty() && rest.ends_with('/')) {
+        return None;
+    }
+
+    if let Some((_, child)) = node.statics.iter().find(|(s, _)| *s == segment)
+        && let Some(found) = find_node(child, path, tail, params)
+        && !found.handlers.is_empty()
+    {
+        return Some(found);
+    }
+
+    let child = node.param.as_deref()?;
+    if params.len == MAX_PARAMS {
+        return None;
+    }
+    let start = path.len() - rest.len();
+    params.params[params.len] = PathParam {
+        start,
+        end: start + segment.len(),
+    };
+    params.len += 1;
+    match find_node(child, path, tail, params) {
+        Some(found) if !found.handlers.is_empty() => Some(found),
+        _ => {
+            params.len -= 1;
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET: Method = Method::GET;
+
+    #[test]
+    fn literals_win_over_params() {
+        assert!(matches!(resolve(&GET, "/"), Some(Route::Catalog)));
+        assert!(matches!(resolve(&GET, "/connect"), Some(Route::Connect)));
+        assert!(matches!(
+            resolve(&GET, "/admin/guests"),
+            Some(Route::Guests)
+        ));
+        assert!(matches!(
+            resolve(&GET, "/api/videos"),
+            Some(Route::Api(Endpoint::Videos))
+        ));
+
+        let path = "/watch/connect";
+        let Some(Route::Watch { alias }) = resolve(&GET, path) else {
+            panic!("{} is not a watch page", path);
+        };
+        assert_eq!(alias.get(path), "connect");
+
+        let path = "/api/keys/k1";
+        let Some(Route::Api(Endpoint::RevokeKey(id))) = resolve(&Method::DELETE, path) else {
+            panic!("{} does not revoke a key", path);
+        };
+        assert_eq!(id.get(path), "k1");
+    }
+
+    #[test]
+    fn backs_out_of_literals_leading_nowhere() {
+        // `/api` and `/admin` only lead to longer routes, so these are videos
+        for path in ["/api", "/admin", "/Movie.mkv"] {
+            let Some(Route::Video { name }) = resolve(&GET, path) else {
+                panic!("{} is not a video", path);
+            };
+            assert_eq!(name.get(path), &path[1..]);
+        }
+        assert!(resolve(&GET, "/api/nothing").is_none());
+        assert!(resolve(&GET, "/watch/a/b").is_none());
+    }
+
+    #[test]
+    fn rejects_trailing_slashes_and_empty_segments() {
+        for path in [
+            "/connect/",
+            "/Movie.mkv/",
+            "/watch/1.mkv/",
+            "/api/videos/",
+            "/watch/",
+            "/watch//1.mkv",
+            "//",
+            "",
+        ] {
+            assert!(resolve(&GET, path).is_none(), "{:?} matched", path);
+        }
+    }
+
+    #[test]
+    fn rejects_other_methods() {
+        assert!(resolve(&Method::POST, "/").is_none());
+        assert!(resolve(&Method::POST, "/connect").is_none());
+        assert!(resolve(&Method::PUT, "/Movie.mkv").is_none());
+        assert!(resolve(&Method::DELETE, "/api/videos").is_none());
+        assert!(resolve(&GET, "/api/beacons").is_none());
+        assert!(matches!(
+            resolve(&Method::POST, "/login"),
+            Some(Route::Login)
+        ));
+    }
+}
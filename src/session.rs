@@ -0,0 +1,259 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hyper::Request;
+
+use crate::VideoEntry;
+use crate::crypto;
+use crate::http;
+use crate::patterns::VideoPatterns;
+
+pub const COOKIE_NAME: &str = "streamshit_session";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Viewer,
+    /// A visitor let in by a guest link.
+    Guest,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+            Role::Guest => "guest",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Role> {
+        match value {
+            "admin" => Some(Role::Admin),
+            "viewer" => Some(Role::Viewer),
+            "guest" => Some(Role::Guest),
+            _ => None,
+        }
+    }
+}
+
+/// A verified, unexpired session decoded from the session cookie.
+#[derive(Clone)]
+pub struct Session {
+    pub user: String,
+    pub role: Role,
+    /// The videos a guest may watch; everyone else may watch them all.
+    pub videos: Option<VideoPatterns>,
+}
+
+impl Session {
+    pub fn allows(&self, video: &VideoEntry) -> bool {
+        self.videos
+            .as_ref()
+            .is_none_or(|videos| videos.matches(video))
+    }
+}
+
+/// Issues and verifies signed session cookies.
+///
+/// Cookies are signed with the current secret. Secrets listed as previous
+/// are still accepted when verifying, so a secret can be rotated without
+/// logging every device out; dropping an old secret from that list (or
+/// starting without a configured secret, which generates a random one)
+/// invalidates every cookie it signed.
+///
+/// When serving HTTPS, cookies are marked `Secure` so a browser never sends
+/// them over a plain connection.
+pub struct Sessions {
+    password: Option<String>,
+    sso: bool,
+    secret: Vec<u8>,
+    previous_secrets: Vec<Vec<u8>>,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl Sessions {
+    pub fn new(
+        password: Option<String>,
+        sso: bool,
+        secret: Option<String>,
+        previous_secrets: Vec<String>,
+        ttl: Duration,
+        secure: bool,
+    ) -> Self {
+        let secret = match secret {
+            Some(secret) => secret.into_bytes(),
+            None => crypto::random_bytes(32),
+        };
+
+        Sessions {
+            password,
+            sso,
+            secret,
+            previous_secrets: previous_secrets
+                .into_iter()
+                .map(String::into_bytes)
+                .collect(),
+            ttl,
+            secure,
+        }
+    }
+
+    /// Whether a login is required at all.
+    pub fn is_enabled(&self) -> bool {
+        self.password.is_some() || self.sso
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn has_sso(&self) -> bool {
+        self.sso
+    }
+
+    pub fn check_password(&self, candidate: &str) -> bool {
+        match &self.password {
+            Some(password) => crypto::constant_time_eq(password.as_bytes(), candidate.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Signs a new session for `user`, returning the cookie value.
+    pub fn issue(&self, user: &str, role: Role) -> String {
+        self.issue_until(user, role, now() + self.ttl.as_secs())
+    }
+
+    /// Signs a session for `user` that ends at `expires` (Unix time).
+    pub fn issue_until(&self, user: &str, role: Role, expires: u64) -> String {
+        let payload = format!("{}:{}:{}", expires, role.as_str(), user);
+        let signature = crypto::hmac_sha256(&self.secret, payload.as_bytes());
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    /// Verifies a cookie value against the current and previous secrets.
+    pub fn verify(&self, value: &str) -> Option<Session> {
+        let (payload, signature) = value.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        let signed_by_known_secret = std::iter::once(&self.secret)
+            .chain(&self.previous_secrets)
+            .any(|secret| {
+                crypto::constant_time_eq(&crypto::hmac_sha256(secret, &payload), &signature)
+            });
+        if !signed_by_known_secret {
+            return None;
+        }
+
+        let payload = String::from_utf8(payload).ok()?;
+        let mut fields = payload.splitn(3, ':');
+        let expires = fields.next()?.parse::<u64>().ok()?;
+        let role = Role::parse(fields.next()?)?;
+        let user = fields.next()?;
+        if expires <= now() {
+            return None;
+        }
+
+        Some(Session {
+            user: user.to_string(),
+            role,
+            videos: None,
+        })
+    }
+
+    pub fn authenticate<B>(&self, req: &Request<B>) -> Option<Session> {
+        self.verify(&http::cookie(req, COOKIE_NAME)?)
+    }
+
+    pub fn set_cookie(&self, value: &str) -> String {
+        self.set_cookie_until(value, now() + self.ttl.as_secs())
+    }
+
+    /// The cookie for a session issued until `expires`.
+    pub fn set_cookie_until(&self, value: &str, expires: u64) -> String {
+        format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+            COOKIE_NAME,
+            value,
+            expires.saturating_sub(now()),
+            self.secure_flag()
+        )
+    }
+
+    pub fn clear_cookie(&self) -> String {
+        format!(
+            "{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0{}",
+            COOKIE_NAME,
+            self.secure_flag()
+        )
+    }
+
+    /// The attribute to append to any login cookie, empty over plain HTTP.
+    pub fn secure_flag(&self) -> &'static str {
+        if self.secure { "; Secure" } else { "" }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn generate_login_html(sessions: &Sessions, error: Option<&str>) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>Streamshit - Login</title>
+    <style>
+        body { font-family: Arial, sans-serif; margin: 40px; }
+        h1 { color: #333; }
+        .login-form {
+            background-color: #f5f5f5;
+            padding: 15px;
+            border-radius: 5px;
+            max-width: 320px;
+        }
+        .login-form input { padding: 8px; margin: 5px 0; width: 100%; box-sizing: border-box; }
+        .login-error { color: #c00; margin-bottom: 10px; }
+        .login-sso { display: block; margin-top: 10px; color: #007bff; }
+    </style>
+</head>
+<body>
+    <h1>Streamshit</h1>
+"#,
+    );
+
+    if let Some(error) = error {
+        html.push_str(&format!(
+            "<div class=\"login-error\">{}</div>",
+            http::html_escape(error)
+        ));
+    }
+
+    html.push_str("<div class=\"login-form\">");
+    if sessions.has_password() {
+        html.push_str(
+            r#"<form method="post" action="/login">
+            <input type="password" name="password" placeholder="Password" autofocus>
+            <input type="submit" value="Log in">
+        </form>"#,
+        );
+    }
+    if sessions.has_sso() {
+        html.push_str(r#"<a class="login-sso" href="/auth/login">Log in with single sign-on</a>"#);
+    }
+    html.push_str("</div>");
+
+    html.push_str("</body></html>");
+    html
+}
@@ -0,0 +1,69 @@
+//! State shared by every request: built once at startup and handed to the
+//! middleware and handlers behind one `Arc`.
+
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::analytics::Analytics;
+use crate::auth::Auth;
+use crate::connect::Addresses;
+use crate::kids::KidProfile;
+use crate::maintenance::Maintenance;
+use crate::storage::DiskMonitor;
+use crate::transport::TransportConfig;
+use crate::{VideoEntry, get_video_list};
+
+pub struct AppState {
+    pub library: Library,
+    pub config: Config,
+    pub auth: Auth,
+    pub analytics: Arc<Analytics>,
+    pub maintenance: Maintenance,
+    pub addresses: Addresses,
+    pub disk: Arc<DiskMonitor>,
+}
+
+/// Settings fixed for the life of the server.
+pub struct Config {
+    /// ffmpeg binary used to serve episodes.
+    #[cfg(feature = "transcoding")]
+    pub ffmpeg: String,
+    pub transport: TransportConfig,
+    pub kids: KidProfile,
+}
+
+/// The videos in the served directory, as of the last scan.
+pub struct Library {
+    dir: String,
+    videos: RwLock<Vec<VideoEntry>>,
+}
+
+impl Library {
+    pub fn scan(dir: String) -> Self {
+        let videos = get_video_list(&dir);
+        Library {
+            dir,
+            videos: RwLock::new(videos),
+        }
+    }
+
+    pub fn videos(&self) -> RwLockReadGuard<'_, Vec<VideoEntry>> {
+        self.videos.read().unwrap()
+    }
+
+    /// Scans the directory again and replaces the catalog with the result.
+    pub fn rescan(&self) {
+        let videos = get_video_list(&self.dir);
+        *self.videos.write().unwrap() = videos;
+    }
+
+    /// The video with this alias or file name.
+    pub fn find(&self, name: &str) -> Option<VideoEntry> {
+        self.videos()
+            .iter()
+            .find(|video| {
+                video.alias == name
+                    || video.path.file_name().and_then(|file| file.to_str()) == Some(name)
+            })
+            .cloned()
+    }
+}
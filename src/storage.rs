@@ -0,0 +1,152 @@
+//! Watches how fast video files come off the disk, so that buffering caused
+//! by slow storage (a sleeping USB drive, a busy NAS) is told apart from a
+//! slow network.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::http;
+
+/// Without a known bitrate, reads slower than this count as slow.
+const SLOW_READ: Duration = Duration::from_millis(250);
+
+/// Reads over which the sustained read rate is measured.
+const MEASURED_READS: usize = 16;
+
+/// How long a warning stays on the catalog.
+const WARNING_TTL: Duration = Duration::from_secs(3600);
+
+/// Warnings kept, one per file.
+const MAX_WARNINGS: usize = 10;
+
+/// A stream the disk could not keep up with.
+#[derive(Clone)]
+pub struct SlowDisk {
+    pub path: PathBuf,
+    /// Bytes per second the video needs to play without stalling.
+    pub needed: u64,
+    /// Bytes per second the disk delivered.
+    pub achieved: u64,
+    pub at: SystemTime,
+}
+
+#[derive(Default)]
+pub struct DiskMonitor {
+    warnings: Mutex<Vec<SlowDisk>>,
+}
+
+impl DiskMonitor {
+    /// Starts watching the reads of one stream of `path`, which plays at
+    /// `bitrate` bytes per second if known.
+    pub fn watch(self: &Arc<Self>, path: &Path, bitrate: Option<u64>) -> ReadWatch {
+        ReadWatch {
+            monitor: self.clone(),
+            path: path.to_path_buf(),
+            bitrate,
+            reads: VecDeque::with_capacity(MEASURED_READS),
+            warned: false,
+        }
+    }
+
+    /// Recent warnings, newest first.
+    pub fn warnings(&self) -> Vec<SlowDisk> {
+        let mut warnings = self.warnings.lock().unwrap();
+        warnings.retain(|warning| warning.at.elapsed().is_ok_and(|age| age < WARNING_TTL));
+        warnings.clone()
+    }
+
+    fn warn(&self, warning: SlowDisk) {
+        eprintln!(
+            "Disk too slow for {}: read {}, needs {}",
+            warning.path.display(),
+            format_rate(warning.achieved),
+            format_rate(warning.needed)
+        );
+        let mut warnings = self.warnings.lock().unwrap();
+        warnings.retain(|existing| existing.path != warning.path);
+        warnings.insert(0, warning);
+        warnings.truncate(MAX_WARNINGS);
+    }
+}
+
+/// Read timings of one stream.
+pub struct ReadWatch {
+    monitor: Arc<DiskMonitor>,
+    path: PathBuf,
+    bitrate: Option<u64>,
+    reads: VecDeque<(usize, Duration)>,
+    warned: bool,
+}
+
+impl ReadWatch {
+    /// Records a read of `bytes` that took `elapsed`, and returns whether it
+    /// was slower than playing those bytes takes, so more should be read
+    /// ahead.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) -> bool {
+        if self.reads.len() == MEASURED_READS {
+            self.reads.pop_front();
+        }
+        self.reads.push_back((bytes, elapsed));
+
+        let Some(bitrate) = self.bitrate else {
+            return elapsed > SLOW_READ;
+        };
+
+        if !self.warned && self.reads.len() == MEASURED_READS {
+            let total_bytes: usize = self.reads.iter().map(|(bytes, _)| bytes).sum();
+            let total_time: Duration = self.reads.iter().map(|(_, elapsed)| *elapsed).sum();
+            let achieved = (total_bytes as f64 / total_time.as_secs_f64().max(1e-6)) as u64;
+            if achieved < bitrate {
+                self.warned = true;
+                self.monitor.warn(SlowDisk {
+                    path: self.path.clone(),
+                    needed: bitrate,
+                    achieved,
+                    at: SystemTime::now(),
+                });
+            }
+        }
+
+        elapsed.as_secs_f64() > bytes as f64 / bitrate as f64
+    }
+}
+
+fn format_rate(bytes_per_second: u64) -> String {
+    format!("{:.1} MB/s", bytes_per_second as f64 / 1_000_000.0)
+}
+
+/// The notice shown to admins on the catalog, empty without warnings.
+pub fn generate_warning_html(warnings: &[SlowDisk]) -> String {
+    if warnings.is_empty() {
+        return String::new();
+    }
+
+    let mut html = String::from(
+        "<div class=\"disk-warning\"><strong>The disk is too slow for some videos.</strong> \
+         Buffering while these played was caused by storage, not the network:<ul>",
+    );
+    for warning in warnings {
+        let minutes = warning
+            .at
+            .elapsed()
+            .map(|age| age.as_secs() / 60)
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<li>{} read at {}, needs {} ({} min ago)</li>",
+            http::html_escape(
+                warning
+                    .path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or_default()
+            ),
+            format_rate(warning.achieved),
+            format_rate(warning.needed),
+            minutes
+        ));
+    }
+    html.push_str("</ul></div>");
+    html
+}
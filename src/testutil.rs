@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the paths handed out, so tests running in parallel never share
+/// one even when they pick the same name.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A path of its own in the temporary directory, ending in `name`. Nothing
+/// is created there.
+pub fn temp_path(name: &str) -> PathBuf {
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("streamshit-{}-{}-{}", std::process::id(), n, name))
+}
+
+/// Writes `data` to a file of its own in the temporary directory.
+pub fn temp_file(name: &str, data: impl AsRef<[u8]>) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, data).unwrap();
+    path
+}
@@ -0,0 +1,662 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Frame, SizeHint};
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::{HeaderMap, Response};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::process::{Child, ChildStdout};
+use tokio::sync::{Notify, mpsc};
+use tokio::time::Sleep;
+
+use crate::gzip;
+use crate::routes::Route;
+use crate::storage::ReadWatch;
+
+/// Most a streamed file is read ahead of the client.
+const MAX_READAHEAD: u64 = 32 * 1024 * 1024;
+
+/// Groups of routes that share a delivery strategy.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    /// HTML pages: the catalog, connect, player, admin, login and SSO pages,
+    /// and the not-found page for paths no route matches.
+    Catalog,
+    /// Video files.
+    Video,
+    /// JSON under `/api`.
+    Api,
+}
+
+impl RouteClass {
+    pub fn of(route: Option<&Route>) -> RouteClass {
+        match route {
+            Some(Route::Api(_)) => RouteClass::Api,
+            Some(Route::Video { .. }) => RouteClass::Video,
+            _ => RouteClass::Catalog,
+        }
+    }
+}
+
+/// How file contents get from the disk into the response.
+///
+/// There is no sendfile: hyper takes the body as `Bytes` frames and writes
+/// them into its own buffered connection, which never hands the socket out
+/// for the kernel to copy a file into (and TLS encrypts in user space
+/// anyway). `Mmap` is the closest this body model allows.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Read the whole requested range into memory first.
+    Buffered,
+    /// Read and send one chunk at a time.
+    Streamed,
+    /// Map the file into memory and send slices of the mapping without
+    /// copying (Unix only; falls back to streamed elsewhere). A file
+    /// truncated while mapped crashes the server; see [`map_file`].
+    Mmap,
+}
+
+#[derive(Clone)]
+pub struct Strategy {
+    /// Gzip generated responses for clients that accept it. Video files are
+    /// never compressed, they already are.
+    pub compression: bool,
+    pub delivery: Delivery,
+    /// Bytes per second per response.
+    pub rate_limit: Option<u64>,
+    pub chunk_size: usize,
+}
+
+/// Delivery strategy per route class, loaded from `--transport-config`.
+///
+/// The file is a list of `[catalog]`, `[video]` and `[api]` sections with
+/// `compression = gzip|off`, `delivery = buffered|streamed|mmap`,
+/// `rate_limit = off|<bytes per second>` and `chunk_size = <bytes>` entries;
+/// sizes accept K, M and G suffixes. Anything left out keeps its default.
+pub struct TransportConfig {
+    catalog: Strategy,
+    video: Strategy,
+    api: Strategy,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        let generated = Strategy {
+            compression: true,
+            delivery: Delivery::Buffered,
+            rate_limit: None,
+            chunk_size: 64 * 1024,
+        };
+
+        TransportConfig {
+            catalog: generated.clone(),
+            video: Strategy {
+                compression: false,
+                delivery: Delivery::Streamed,
+                rate_limit: None,
+                chunk_size: 256 * 1024,
+            },
+            api: generated,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut config = TransportConfig::default();
+        let mut section: Option<&mut Strategy> = None;
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(match name.trim() {
+                    "catalog" => &mut config.catalog,
+                    "video" => &mut config.video,
+                    "api" => &mut config.api,
+                    other => return Err(error(format!("unknown route class {}", other))),
+                });
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected key = value".to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            let strategy = section
+                .as_deref_mut()
+                .ok_or_else(|| error(format!("{} outside of a section", key)))?;
+
+            match key {
+                "compression" => {
+                    strategy.compression = match value {
+                        "gzip" => true,
+                        "off" => false,
+                        _ => return Err(error(format!("unknown compression {}", value))),
+                    }
+                }
+                "delivery" => {
+                    strategy.delivery = match value {
+                        "buffered" => Delivery::Buffered,
+                        "streamed" => Delivery::Streamed,
+                        "mmap" => Delivery::Mmap,
+                        "sendfile" => {
+                            return Err(error(
+                                "sendfile is not supported, use mmap instead".to_string(),
+                            ));
+                        }
+                        _ => return Err(error(format!("unknown delivery {}", value))),
+                    }
+                }
+                "rate_limit" => {
+                    // A limit of zero in any unit means no limit
+                    strategy.rate_limit = match value {
+                        "off" => None,
+                        _ => Some(parse_size(value).map_err(error)?).filter(|&rate| rate > 0),
+                    }
+                }
+                "chunk_size" => {
+                    strategy.chunk_size = parse_size(value).map_err(error)?.max(1) as usize;
+                }
+                _ => return Err(error(format!("unknown setting {}", key))),
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn strategy(&self, class: RouteClass) -> &Strategy {
+        match class {
+            RouteClass::Catalog => &self.catalog,
+            RouteClass::Video => &self.video,
+            RouteClass::Api => &self.api,
+        }
+    }
+
+    /// Gzips a generated (in-memory) response if the class's strategy asks
+    /// for it and the client accepts it. Streamed bodies pass unchanged.
+    pub fn compress(
+        &self,
+        class: RouteClass,
+        accepts_gzip: bool,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let strategy = self.strategy(class);
+        if !strategy.compression || !accepts_gzip || !is_compressible(response.headers()) {
+            return response;
+        }
+        let (mut parts, mut body) = response.into_parts();
+        let bytes = match body.take_bytes() {
+            Some(bytes) if bytes.len() >= 256 => bytes,
+            Some(bytes) => return Response::from_parts(parts, Body::from_bytes(bytes, strategy)),
+            None => return Response::from_parts(parts, body),
+        };
+
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+        parts
+            .headers
+            .append(VARY, "Accept-Encoding".parse().unwrap());
+        parts.headers.remove(CONTENT_LENGTH);
+        let compressed = Bytes::from(gzip::compress(&bytes));
+        Response::from_parts(parts, Body::from_bytes(compressed, strategy))
+    }
+}
+
+/// Collects a generated response into a [`Body`] sent per `strategy`.
+pub async fn from_page(response: Response<Full<Bytes>>, strategy: &Strategy) -> Response<Body> {
+    let (parts, body) = response.into_parts();
+    let bytes = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(never) => match never {},
+    };
+    Response::from_parts(parts, Body::from_bytes(bytes, strategy))
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("text/") || value.starts_with("application/json"))
+        .unwrap_or(false)
+}
+
+/// Whether the client listed gzip in Accept-Encoding (and didn't refuse it
+/// with `q=0`).
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name == "gzip" || name == "*") && !refused
+        })
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1024),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {}", value))
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes into an
+/// inclusive `(start, end)`. `None` means the header should be ignored and
+/// the whole file sent; `Some(Err(()))` means it cannot be satisfied.
+pub fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => len.saturating_sub(1),
+            end => end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+        };
+        if start >= len || start > end {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+    Some(Ok(range))
+}
+
+enum Source {
+    Memory(Bytes),
+    /// Chunks of a file, read ahead by [`read_ahead`].
+    File {
+        chunks: mpsc::UnboundedReceiver<io::Result<Bytes>>,
+        progress: Arc<Progress>,
+    },
+    /// Output of a child process of unknown length; the child is killed if
+    /// the client goes away first.
+    #[cfg_attr(not(feature = "transcoding"), allow(dead_code))]
+    Process {
+        _child: Child,
+        stdout: ChildStdout,
+    },
+}
+
+/// How far the client got, for the read-ahead task to keep its distance.
+struct Progress {
+    consumed: AtomicU64,
+    notify: Notify,
+}
+
+/// Reads `len` bytes of `file` in chunks, staying up to a window ahead of
+/// what the client took. The window starts at one chunk and doubles after
+/// every read slower than the video plays, so a slow or stalling disk gets
+/// a growing buffer to hide behind.
+async fn read_ahead(
+    mut file: tokio::fs::File,
+    len: u64,
+    chunk_size: usize,
+    chunks: mpsc::UnboundedSender<io::Result<Bytes>>,
+    progress: Arc<Progress>,
+    mut watch: ReadWatch,
+) {
+    let mut window = chunk_size as u64;
+    let mut read = 0;
+    while read < len {
+        while read - progress.consumed.load(Ordering::Acquire) >= window {
+            tokio::select! {
+                _ = progress.notify.notified() => {}
+                _ = chunks.closed() => return,
+            }
+        }
+
+        let mut buffer = vec![0; (chunk_size as u64).min(len - read) as usize];
+        let started = Instant::now();
+        let count = match file.read(&mut buffer).await {
+            // Ending early is reported by the body
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(err) => {
+                let _ = chunks.send(Err(err));
+                return;
+            }
+        };
+        if watch.record(count, started.elapsed()) {
+            window = (window * 2).min(MAX_READAHEAD.max(chunk_size as u64));
+        }
+
+        buffer.truncate(count);
+        read += count as u64;
+        if chunks.send(Ok(Bytes::from(buffer))).is_err() {
+            return;
+        }
+    }
+}
+
+struct Throttle {
+    rate: u64,
+    start: Instant,
+    sent: u64,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    /// Waits until sending more stays within the configured rate.
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let allowed_at =
+                self.start + Duration::from_secs_f64(self.sent as f64 / self.rate as f64);
+            if allowed_at <= Instant::now() {
+                return Poll::Ready(());
+            }
+            self.sleep = Some(Box::pin(tokio::time::sleep_until(allowed_at.into())));
+        }
+    }
+}
+
+/// Response body sent in a [`Strategy`]'s chunk size, optionally at a
+/// limited rate.
+pub struct Body {
+    source: Source,
+    remaining: u64,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    throttle: Option<Throttle>,
+}
+
+impl Body {
+    pub fn from_bytes(bytes: Bytes, strategy: &Strategy) -> Self {
+        let remaining = bytes.len() as u64;
+        Self::new(Source::Memory(bytes), remaining, strategy.chunk_size)
+    }
+
+    /// Sends `bytes` in a single chunk, for small generated responses.
+    pub fn whole(bytes: Bytes) -> Self {
+        let remaining = bytes.len() as u64;
+        Self::new(
+            Source::Memory(bytes),
+            remaining,
+            (remaining as usize).max(1),
+        )
+    }
+
+    /// Streams `len` bytes from the file's current position, reading ahead
+    /// of the client as `watch` finds the disk needs.
+    pub fn from_file(
+        file: tokio::fs::File,
+        len: u64,
+        strategy: &Strategy,
+        watch: ReadWatch,
+    ) -> Self {
+        let (sender, chunks) = mpsc::unbounded_channel();
+        let progress = Arc::new(Progress {
+            consumed: AtomicU64::new(0),
+            notify: Notify::new(),
+        });
+        tokio::spawn(read_ahead(
+            file,
+            len,
+            strategy.chunk_size,
+            sender,
+            progress.clone(),
+            watch,
+        ));
+        Self::new(Source::File { chunks, progress }, len, strategy.chunk_size)
+    }
+
+    /// Streams the child's stdout until it closes. The child must have been
+    /// spawned with a piped stdout and `kill_on_drop`.
+    #[cfg_attr(not(feature = "transcoding"), allow(dead_code))]
+    pub fn from_process(mut child: Child, strategy: &Strategy) -> Self {
+        let stdout = child.stdout.take().expect("child stdout is piped");
+        Self::new(
+            Source::Process {
+                _child: child,
+                stdout,
+            },
+            u64::MAX,
+            strategy.chunk_size,
+        )
+    }
+
+    fn new(source: Source, remaining: u64, chunk_size: usize) -> Self {
+        Body {
+            source,
+            remaining,
+            chunk_size,
+            buffer: Vec::new(),
+            throttle: None,
+        }
+    }
+
+    /// Sends at most `rate` bytes per second from now on.
+    pub fn with_rate_limit(mut self, rate: u64) -> Self {
+        self.throttle = Some(Throttle {
+            rate,
+            start: Instant::now(),
+            sent: 0,
+            sleep: None,
+        });
+        self
+    }
+
+    /// Takes the contents out, if they are all in memory and unthrottled.
+    pub fn take_bytes(&mut self) -> Option<Bytes> {
+        match &mut self.source {
+            Source::Memory(bytes) if self.throttle.is_none() => {
+                self.remaining = 0;
+                Some(std::mem::take(bytes))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl hyper::body::Body for Body {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        if let Some(throttle) = &mut this.throttle {
+            ready!(throttle.poll_ready(cx));
+        }
+
+        let want = (this.chunk_size as u64).min(this.remaining) as usize;
+        let chunk = match &mut this.source {
+            Source::Memory(bytes) => bytes.split_to(want.min(bytes.len())),
+            Source::File { chunks, progress } => match ready!(chunks.poll_recv(cx)) {
+                Some(chunk) => {
+                    let chunk = chunk?;
+                    progress
+                        .consumed
+                        .fetch_add(chunk.len() as u64, Ordering::Release);
+                    progress.notify.notify_one();
+                    chunk
+                }
+                None => Bytes::new(),
+            },
+            Source::Process { stdout, .. } => {
+                this.buffer.resize(want, 0);
+                let mut buf = ReadBuf::new(&mut this.buffer);
+                ready!(Pin::new(stdout).poll_read(cx, &mut buf))?;
+                if buf.filled().is_empty() {
+                    this.remaining = 0;
+                    return Poll::Ready(None);
+                }
+                Bytes::copy_from_slice(buf.filled())
+            }
+        };
+        if chunk.is_empty() {
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file ended early",
+            ))));
+        }
+
+        this.remaining -= chunk.len() as u64;
+        if let Some(throttle) = &mut this.throttle {
+            throttle.sent += chunk.len() as u64;
+        }
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.source {
+            Source::Process { .. } => SizeHint::default(),
+            _ => SizeHint::with_exact(self.remaining),
+        }
+    }
+}
+
+/// Maps `len` bytes of `file` read-only into memory.
+///
+/// The mapping stays valid after the file is closed. If the file is
+/// truncated or rewritten while it is being served, reading the mapping past
+/// its new end raises SIGBUS, which kills the whole server, not just the
+/// response. Only map files that nothing rewrites in place.
+#[cfg(unix)]
+pub fn map_file(file: &fs::File, len: usize) -> io::Result<Bytes> {
+    use std::os::fd::AsRawFd;
+
+    struct Mapping {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    // SAFETY: the mapping is read-only and owned exclusively by this value.
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl AsRef<[u8]> for Mapping {
+        fn as_ref(&self) -> &[u8] {
+            // SAFETY: `ptr` points to `len` mapped, readable bytes until drop.
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            // SAFETY: `ptr`/`len` describe a mapping created by `mmap` below.
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+    // SAFETY: mapping a file descriptor we hold open, read-only and private.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Bytes::from_owner(Mapping { ptr, len }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4M"), Ok(4 * 1024 * 1024));
+        assert!(parse_size("99999999999999999999K").is_err());
+        assert!(parse_size("17179869184G").is_err());
+    }
+
+    #[test]
+    fn turns_off_a_zero_rate_limit() {
+        for value in ["off", "0", "0K"] {
+            let config = TransportConfig::parse(&format!("[video]\nrate_limit = {}\n", value));
+            assert_eq!(config.unwrap().strategy(RouteClass::Video).rate_limit, None);
+        }
+        let config = TransportConfig::parse("[video]\nrate_limit = 1K\n").unwrap();
+        assert_eq!(config.strategy(RouteClass::Video).rate_limit, Some(1024));
+    }
+
+    #[test]
+    fn classifies_resolved_routes() {
+        let class =
+            |path| RouteClass::of(crate::routes::resolve(&hyper::Method::GET, path).as_ref());
+        for path in ["/", "/kids", "/kids/leave", "/guest/abc", "/watch/1"] {
+            assert!(class(path) == RouteClass::Catalog, "{}", path);
+        }
+        assert!(class("/api/videos") == RouteClass::Api);
+        assert!(class("/1.mkv") == RouteClass::Video);
+        assert!(class("/no/such/page") == RouteClass::Catalog);
+    }
+
+    #[test]
+    fn rejects_sendfile() {
+        assert!(TransportConfig::parse("[video]\ndelivery = sendfile\n").is_err());
+    }
+}